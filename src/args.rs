@@ -1,9 +1,15 @@
 
-use clap::{Arg, App, ArgMatches, ArgGroup};
+use clap::{Arg, App, ArgMatches, ArgGroup, SubCommand};
 //use clap::*;
 
 
 pub fn parse_args<'a>() -> ArgMatches<'a> {
+    app().get_matches()
+}
+
+
+/// Options and subcommands, also used to check the config file
+pub fn app<'a, 'b>() -> App<'a, 'b> {
     App::new("stest (speedtest cli)")
         .version(crate_version!())
         .author(crate_authors!())
@@ -14,21 +20,303 @@ pub fn parse_args<'a>() -> ArgMatches<'a> {
             .value_name("number_tests")
             .help("Sets number of tests to run")
             .takes_value(true))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("file")
+            .help("Sets config file, defaults to ~/.config/stest/config.toml when it exists. Command line options override values in the file")
+            .takes_value(true))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .value_name("name")
+            .help("Uses values of [profiles.<name>] from config file")
+            .takes_value(true))
+        .arg(Arg::with_name("interval")
+            .long("interval")
+            .value_name("secs")
+            .help("Keeps running tests, waiting this many seconds (at least 60) between runs")
+            .takes_value(true))
+        .arg(Arg::with_name("speedtest_config")
+            .long("speedtest-config")
+            .value_name("file")
+            .help("Uses speedtest config XML saved from speedtest.net/speedtest-config.php instead of fetching it")
+            .takes_value(true))
         .arg(Arg::with_name("csv")
             .short("c")
             .long("csv")
             .value_name("csv")
             .help("Set name of csv file")
             .takes_value(true))
+        .arg(Arg::with_name("json")
+            .short("j")
+            .long("json")
+            .value_name("json")
+            .help("Set name of json file")
+            .takes_value(true))
         .arg(Arg::with_name("use_cached")
             .short("u")
             .long("use-cached")
             .help("Use a cached copy of servers"))
+        .arg(Arg::with_name("https")
+            .long("https")
+            .help("Use https for config, server list and test traffic"))
+        .arg(Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("proxy")
+            .help("HTTP proxy as host:port, defaults to HTTP_PROXY/HTTPS_PROXY environment variables")
+            .takes_value(true))
+        .arg(Arg::with_name("no_proxy")
+            .long("no-proxy")
+            .value_name("hosts")
+            .help("Comma separated hosts that should not go through proxy, added to NO_PROXY")
+            .takes_value(true))
+        .arg(Arg::with_name("user_agent")
+            .long("user-agent")
+            .value_name("user_agent")
+            .help("User-Agent sent with every request")
+            .takes_value(true))
+        .arg(Arg::with_name("header")
+            .long("header")
+            .value_name("header")
+            .help("Custom header sent with every request as 'Name: value', can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("connections")
+            .long("connections")
+            .value_name("n")
+            .help("Number of worker threads, each with one persistent connection, used for download and upload tests (default 8)")
+            .takes_value(true))
+        .arg(Arg::with_name("connect_timeout")
+            .long("connect-timeout")
+            .value_name("secs")
+            .help("Connect timeout in seconds (default 30)")
+            .takes_value(true))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("secs")
+            .help("Read/write timeout in seconds for config, server list, ping and download requests (default 10)")
+            .takes_value(true))
+        .arg(Arg::with_name("upload_timeout")
+            .long("upload-timeout")
+            .value_name("secs")
+            .help("Read/write timeout in seconds for upload requests (default 5)")
+            .takes_value(true))
+        .arg(Arg::with_name("download_cutoff")
+            .long("download-cutoff")
+            .value_name("secs")
+            .help("Stop download test after this many seconds (default 10)")
+            .takes_value(true))
+        .arg(Arg::with_name("max_bytes")
+            .long("max-bytes")
+            .value_name("bytes")
+            .help("Stops download and upload tests once together they transferred this many bytes, e.g. 100M or 1GiB")
+            .takes_value(true))
+        .arg(Arg::with_name("monthly_budget")
+            .long("monthly-budget")
+            .value_name("bytes")
+            .help("Skips runs once tests transferred this many bytes in the current month, e.g. 5G")
+            .takes_value(true))
+        .arg(Arg::with_name("usage_file")
+            .long("usage-file")
+            .value_name("file")
+            .help("Sets path of the file tracking data used this month (defaults to ~/.stest/usage)")
+            .takes_value(true))
+        .arg(Arg::with_name("retries")
+            .long("retries")
+            .value_name("n")
+            .help("Number of times fetching speedtest config is retried (default 10)")
+            .takes_value(true))
+        .arg(Arg::with_name("retry_delay")
+            .long("retry-delay")
+            .value_name("ms")
+            .help("Delay in ms before first retry, doubled on every further retry up to a minute (default 1000)")
+            .takes_value(true))
+        .arg(Arg::with_name("ping_method")
+            .long("ping-method")
+            .value_name("http|tcp")
+            .help("Measure latency as time to fetch latency.txt (http, default) or TCP handshake time (tcp)")
+            .possible_values(&["http", "tcp"])
+            .takes_value(true))
+        .arg(Arg::with_name("transport")
+            .long("transport")
+            .value_name("http|ookla")
+            .help("Run latency, download and upload tests over legacy HTTP endpoints (http, default) or the Ookla TCP protocol on the server's host:port (ookla)")
+            .possible_values(&["http", "ookla"])
+            .takes_value(true))
+        .arg(Arg::with_name("source")
+            .long("source")
+            .value_name("ip")
+            .help("Source address every connection is bound to")
+            .takes_value(true))
+        .arg(Arg::with_name("interface")
+            .long("interface")
+            .value_name("name")
+            .help("Network interface every connection is bound to (linux only)")
+            .takes_value(true))
+        .arg(Arg::with_name("no_loaded_latency")
+            .long("no-loaded-latency")
+            .help("Do not measure latency during download and upload tests (bufferbloat)"))
+        .arg(Arg::with_name("no_download")
+            .long("no-download")
+            .help("Skips the download test"))
+        .arg(Arg::with_name("no_upload")
+            .long("no-upload")
+            .help("Skips the upload test"))
+        .arg(Arg::with_name("ping_only")
+            .long("ping-only")
+            .help("Only measures latency, skipping download and upload tests e.g. for frequent checks on metered links")
+            .conflicts_with_all(&["no_download", "no_upload"]))
+        .arg(Arg::with_name("compare")
+            .long("compare")
+            .value_name("n")
+            .help("Runs the tests against the n servers with lowest latency and compares them")
+            .takes_value(true))
+        .arg(Arg::with_name("compare_ids")
+            .long("compare-ids")
+            .value_name("ids")
+            .help("Runs the tests against the servers with given comma separated ids and compares them")
+            .takes_value(true)
+            .conflicts_with("compare"))
+        .arg(Arg::with_name("server_strategy")
+            .long("server-strategy")
+            .value_name("strategy")
+            .help("How the server of every test is picked - fixed (best server, default), round-robin or random over the best --top-k servers, or re-ping-each to ping them again before every test")
+            .possible_values(&["fixed", "round-robin", "random", "re-ping-each"])
+            .takes_value(true)
+            .conflicts_with_all(&["compare", "compare_ids"]))
+        .arg(Arg::with_name("top_k")
+            .long("top-k")
+            .value_name("k")
+            .help("Number of best servers --server-strategy picks from (default 5)")
+            .takes_value(true))
+        .arg(Arg::with_name("simple")
+            .long("simple")
+            .help("Only prints ping, download and upload speed of every test on stdout, everything else goes to stderr")
+            .conflicts_with("tui"))
+        .arg(Arg::with_name("tui")
+            .long("tui")
+            .help("Shows a full screen dashboard with live throughput, latency, server details and results of every test"))
+        .arg(Arg::with_name("progress_listen")
+            .long("progress-listen")
+            .value_name("address")
+            .help("Streams progress of tests as JSON messages to WebSocket clients connecting to given address, e.g. 127.0.0.1:8001")
+            .takes_value(true))
+        .arg(Arg::with_name("ipv4")
+            .short("4")
+            .long("ipv4")
+            .help("Only use IPv4 connections"))
+        .arg(Arg::with_name("ipv6")
+            .short("6")
+            .long("ipv6")
+            .help("Only use IPv6 connections"))
+        .arg(Arg::with_name("dual_stack")
+            .long("dual-stack")
+            .help("Run every test over both IPv4 and IPv6 against the same server and compare results"))
+        .group(ArgGroup::with_name("ip-version")
+            .args(&["ipv4", "ipv6", "dual_stack"]))
+        .arg(Arg::with_name("min_download")
+            .long("min-download")
+            .value_name("warning,critical")
+            .help("Download speed in Mbps below which status is WARNING/CRITICAL (Nagios exit codes)")
+            .takes_value(true))
+        .arg(Arg::with_name("min_upload")
+            .long("min-upload")
+            .value_name("warning,critical")
+            .help("Upload speed in Mbps below which status is WARNING/CRITICAL (Nagios exit codes)")
+            .takes_value(true))
+        .arg(Arg::with_name("max_latency")
+            .long("max-latency")
+            .value_name("warning,critical")
+            .help("Latency in ms above which status is WARNING/CRITICAL (Nagios exit codes)")
+            .takes_value(true))
+        .arg(Arg::with_name("history_db")
+            .long("history-db")
+            .value_name("history_db")
+            .help("Sets path of the results history database (defaults to ~/.stest/history.db)")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("units")
+            .long("units")
+            .value_name("unit")
+            .help("Unit speeds are shown in and written to csv/json files (default Mbps), raw bytes and millis are unchanged")
+            .possible_values(&["kbps", "Mbps", "Gbps", "kB/s", "MB/s", "GB/s", "KiB/s", "MiB/s", "GiB/s"])
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("precision")
+            .long("precision")
+            .value_name("n")
+            .help("Number of decimal places of speeds (default 2 on the console, full precision in csv/json files)")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .multiple(true)
+            .help("Logs more to stderr, -v for info, -vv for HTTP status codes, timeouts and outcome of every request, -vvv for everything")
+            .global(true))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("Only prints results and errors")
+            .conflicts_with("verbose")
+            .global(true))
+        .arg(Arg::with_name("log_format")
+            .long("log-format")
+            .value_name("format")
+            .help("Format of log lines on stderr (default text)")
+            .possible_values(&["text", "json"])
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("no_history")
+            .long("no-history")
+            .help("Do not store results in the history database"))
         .args_from_usage(
             "-s --server-country [server_country] 'This will scan servers only from given country name - it might take a while before it finds the best server'
              -o --server-country-code [server_country_code]  'This will scan servers only from given country code - it might take a while before it finds the best server'")
         .group(ArgGroup::with_name("server-filter")
           .args(&["server-country", "server-country-code"]))
+        .subcommand(SubCommand::with_name("history")
+            .about("Lists and summarises results of previous runs")
+            .arg(Arg::with_name("from")
+                .short("f")
+                .long("from")
+                .value_name("from")
+                .help("Only show results on or after given date (YYYY-MM-DD)")
+                .takes_value(true))
+            .arg(Arg::with_name("to")
+                .short("t")
+                .long("to")
+                .value_name("to")
+                .help("Only show results on or before given date (YYYY-MM-DD)")
+                .takes_value(true))
+            .arg(Arg::with_name("server")
+                .short("s")
+                .long("server")
+                .value_name("server")
+                .help("Only show results for given server id or name")
+                .takes_value(true))
+            .arg(Arg::with_name("limit")
+                .short("l")
+                .long("limit")
+                .value_name("limit")
+                .help("Only show latest given number of results")
+                .takes_value(true))
+            .arg(Arg::with_name("summary")
+                .long("summary")
+                .help("Prints percentiles and daily averages instead of individual results")))
+        .subcommand(SubCommand::with_name("daemon")
+            .about("Serves a REST API to trigger tests and fetch results, runs use the other options as defaults")
+            .arg(Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .value_name("listen")
+                .help("Address to listen on, defaults to 127.0.0.1:8000 - there is no authentication so be careful exposing it")
+                .takes_value(true))
+            .arg(Arg::with_name("allow_origin")
+                .long("allow-origin")
+                .value_name("allow_origin")
+                .help("Origin of web pages allowed to call the API, e.g. https://dashboard.example.com")
+                .takes_value(true)))
         //        .subcommand(SubCommand::with_name("server")
         //                .about("Available test servers can be searched for")
         //                .arg(Arg::with_name("list")
@@ -36,6 +324,5 @@ pub fn parse_args<'a>() -> ArgMatches<'a> {
         //                    .long("list")
         //                    .value_name("list")
         //                    .help("prints all servers")))
-        .get_matches()
 }
 
@@ -0,0 +1,128 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use chrono;
+
+const BUDGET_DIR_NAME: &'static str = ".stest";
+const USAGE_FILE_NAME: &'static str = "usage";
+
+
+/// Bytes transferred by tests in a calendar month, kept in a small file so that runs
+/// on metered links can be stopped once the monthly allowance is used up. The file
+/// holds a single "YYYY-MM bytes" line, a new month starts from zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataBudget {
+    pub path: String,
+    pub limit_bytes: u64
+}
+
+
+impl DataBudget {
+    pub fn new(path: &str, limit_bytes: u64) -> DataBudget {
+        DataBudget { path: path.to_string(), limit_bytes: limit_bytes }
+    }
+
+    /// Bytes used so far this month
+    pub fn used(&self) -> Result<u64, String> {
+        let mut content = String::new();
+        match File::open(&self.path) {
+            Ok(mut f)   => {
+                f.read_to_string(&mut content)
+                    .map_err(|e| format!("Cannot read data usage file {} - {}", self.path, e))?;
+            },
+            // Nothing used yet
+            Err(_)      => return Ok(0)
+        }
+        parse_usage(&content, &current_month())
+    }
+
+    pub fn remaining(&self) -> Result<u64, String> {
+        self.used().map(|used| self.limit_bytes.saturating_sub(used))
+    }
+
+    /// Adds bytes of a test to this month's usage
+    pub fn record(&self, bytes: u64) -> Result<(), String> {
+        let month = current_month();
+        let used = self.used()?;
+        File::create(&self.path)
+            .and_then(|mut f| f.write_all(format_usage(&month, used + bytes).as_bytes()))
+            .map_err(|e| format!("Cannot write data usage file {} - {}", self.path, e))
+    }
+}
+
+
+/// Default location of the data usage file - ~/.stest/usage, falls back to current
+/// directory if home directory cannot be found.
+pub fn default_usage_path() -> String {
+    let home = env::var("HOME").or(env::var("USERPROFILE"));
+    match home {
+        Ok(h) => {
+            let mut dir = PathBuf::from(h);
+            dir.push(BUDGET_DIR_NAME);
+            fs::create_dir_all(&dir).ok();
+            dir.push(USAGE_FILE_NAME);
+            dir.to_string_lossy().into_owned()
+        },
+        Err(_) => USAGE_FILE_NAME.to_string()
+    }
+}
+
+
+fn current_month() -> String {
+    chrono::Local::now().format("%Y-%m").to_string()
+}
+
+
+/// Bytes recorded for given month, usage of any other month does not count
+fn parse_usage(content: &str, month: &str) -> Result<u64, String> {
+    let line = content.trim();
+    if line.is_empty() {
+        return Ok(0);
+    }
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(format!("Invalid data usage '{}'", line));
+    }
+    let bytes = parts[1].parse::<u64>().map_err(|_| format!("Invalid data usage '{}'", line))?;
+    Ok(if parts[0] == month { bytes } else { 0 })
+}
+
+
+fn format_usage(month: &str, bytes: u64) -> String {
+    format!("{} {}\n", month, bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{DataBudget, parse_usage, format_usage};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn parse_usage_test() {
+        assert_eq!(Ok(1500), parse_usage("2018-03 1500\n", "2018-03"));
+        assert_eq!(Ok(0), parse_usage("2018-02 1500\n", "2018-03"));
+        assert_eq!(Ok(0), parse_usage("", "2018-03"));
+        assert!(parse_usage("2018-03", "2018-03").is_err());
+        assert!(parse_usage("2018-03 lots", "2018-03").is_err());
+        assert_eq!(Ok(42), parse_usage(&format_usage("2018-03", 42), "2018-03"));
+    }
+
+    #[test]
+    fn record_usage_test() {
+        let mut path = env::temp_dir();
+        path.push(format!("stest-usage-{}", ::rand::random::<u32>()));
+        let budget = DataBudget::new(&path.to_string_lossy(), 1000);
+        assert_eq!(Ok(1000), budget.remaining());
+
+        budget.record(600).unwrap();
+        budget.record(600).unwrap();
+        assert_eq!(Ok(1200), budget.used());
+        assert_eq!(Ok(0), budget.remaining());
+        fs::remove_file(&path).ok();
+    }
+}
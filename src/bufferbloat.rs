@@ -0,0 +1,234 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper;
+
+use client::{ClientOptions, new_pooled_client, default_headers};
+use config::TestServerConfig;
+use pool::drain;
+use stats::percentile;
+use ookla::{OoklaClient, Transport};
+use progress::{Phase, ProgressEvent};
+use latency_url;
+
+const PROBE_INTERVAL_MILLIS: u64 = 200;
+
+
+/// Fetches latency.txt on a side thread every PROBE_INTERVAL_MILLIS until stopped, used
+/// to measure latency while download or upload test saturates the link. Every sample is
+/// reported as progress of given phase.
+pub struct LatencyProbe {
+    stop: Arc<AtomicBool>,
+    samples: Arc<Mutex<Vec<f64>>>
+}
+
+
+impl LatencyProbe {
+    pub fn start(server: &TestServerConfig, options: &ClientOptions, phase: Phase) -> LatencyProbe {
+        let server = server.clone();
+        let options = options.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let probed = samples.clone();
+
+        thread::spawn(move || {
+            let mut sample = latency_sampler(&server, &options);
+            while !stop_flag.load(Ordering::SeqCst) {
+                let latency = sample();
+                // Requests still running when the phase ended are not counted
+                if stop_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Some(latency) = latency {
+                    options.progress.emit(ProgressEvent::Latency { phase: phase, millis: latency as u64 });
+                    if let Ok(mut samples) = probed.lock() {
+                        samples.push(latency);
+                    }
+                }
+                thread::sleep(Duration::from_millis(PROBE_INTERVAL_MILLIS));
+            }
+        });
+
+        LatencyProbe { stop: stop, samples: samples }
+    }
+
+    /// Stops probing and returns median latency in ms, None when no probe succeeded.
+    /// Does not wait for a request in flight, which could take up to the io timeout.
+    pub fn stop(self) -> Option<u64> {
+        self.stop.store(true, Ordering::SeqCst);
+        match self.samples.lock() {
+            Ok(ref samples) if !samples.is_empty()  => Some(percentile(samples, 50.0).round() as u64),
+            _                                       => None
+        }
+    }
+}
+
+
+/// Takes a single latency sample in millis per call - latency.txt fetch over a kept
+/// alive connection, or PING over one Ookla connection when that is the transport.
+fn latency_sampler(server: &TestServerConfig, options: &ClientOptions) -> Box<FnMut() -> Option<f64>> {
+    if options.transport == Transport::Ookla {
+        let mut client = OoklaClient::connect(server, options).ok();
+        return Box::new(move || {
+            client.as_mut()
+                .and_then(|c| c.ping().ok())
+                .map(|d| to_millis(d))
+        });
+    }
+
+    let url = latency_url(server, options);
+    let client = new_pooled_client(options, url.as_str(), options.network.io_timeout, 1);
+    let headers = default_headers(options);
+    Box::new(move || {
+        let start = Instant::now();
+        match client.get(url.as_str()).headers(headers.clone()).send() {
+            Ok(mut res)     => {
+                drain(&mut res).ok();
+                if res.status == hyper::Ok { Some(to_millis(start.elapsed())) } else { None }
+            },
+            Err(_)          => None
+        }
+    })
+}
+
+
+fn to_millis(duration: Duration) -> f64 {
+    (duration.as_secs() * 1_000) as f64 + (duration.subsec_nanos() / 1_000_000) as f64
+}
+
+
+/// Grade of the latency increase under load, same scale as the common bufferbloat tests
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Grade {
+    APlus,
+    A,
+    B,
+    C,
+    D,
+    F
+}
+
+
+impl Grade {
+    pub fn from_increase(increase_millis: u64) -> Grade {
+        if increase_millis < 5 {
+            Grade::APlus
+        } else if increase_millis < 30 {
+            Grade::A
+        } else if increase_millis < 60 {
+            Grade::B
+        } else if increase_millis < 200 {
+            Grade::C
+        } else if increase_millis < 400 {
+            Grade::D
+        } else {
+            Grade::F
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Grade::APlus    => "A+",
+            Grade::A        => "A",
+            Grade::B        => "B",
+            Grade::C        => "C",
+            Grade::D        => "D",
+            Grade::F        => "F"
+        }
+    }
+}
+
+
+/// Idle latency and median latency measured during download and upload tests, nothing
+/// can be compared when the idle ping failed
+#[derive(Debug, Clone, Default)]
+pub struct LoadedLatency {
+    pub idle_millis: Option<u64>,
+    pub download_millis: Option<u64>,
+    pub upload_millis: Option<u64>
+}
+
+
+impl LoadedLatency {
+    pub fn download_increase(&self) -> Option<u64> {
+        match (self.download_millis, self.idle_millis) {
+            (Some(l), Some(idle))   => Some(l.saturating_sub(idle)),
+            _                       => None
+        }
+    }
+
+    pub fn upload_increase(&self) -> Option<u64> {
+        match (self.upload_millis, self.idle_millis) {
+            (Some(l), Some(idle))   => Some(l.saturating_sub(idle)),
+            _                       => None
+        }
+    }
+
+    /// Graded on the worse of the two increases, None without any loaded measurement
+    pub fn grade(&self) -> Option<Grade> {
+        let increases: Vec<u64> = vec![self.download_increase(), self.upload_increase()]
+            .into_iter()
+            .filter_map(|i| i)
+            .collect();
+        increases.into_iter().max().map(Grade::from_increase)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Grade, LatencyProbe, LoadedLatency};
+    use client::ClientOptions;
+    use config::TestServerConfig;
+    use ookla::Transport;
+    use progress::Phase;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn grade_from_increase_test() {
+        assert_eq!(Grade::APlus, Grade::from_increase(0));
+        assert_eq!(Grade::A, Grade::from_increase(5));
+        assert_eq!(Grade::B, Grade::from_increase(59));
+        assert_eq!(Grade::C, Grade::from_increase(60));
+        assert_eq!(Grade::D, Grade::from_increase(399));
+        assert_eq!(Grade::F, Grade::from_increase(400));
+        assert_eq!("A+", Grade::APlus.label());
+    }
+
+    #[test]
+    fn stop_does_not_wait_for_request_test() {
+        // Accepts connections (backlog) but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServerConfig { host: listener.local_addr().unwrap().to_string(), ..TestServerConfig::default() };
+        let options = ClientOptions { transport: Transport::Ookla, ..ClientOptions::default() };
+
+        let probe = LatencyProbe::start(&server, &options, Phase::Download);
+        thread::sleep(Duration::from_millis(300));
+        let stopping = Instant::now();
+        assert_eq!(None, probe.stop());
+        assert!(stopping.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn loaded_latency_grade_test() {
+        let latency = LoadedLatency { idle_millis: Some(20), download_millis: Some(45), upload_millis: Some(150) };
+        assert_eq!(Some(25), latency.download_increase());
+        assert_eq!(Some(130), latency.upload_increase());
+        assert_eq!(Some(Grade::C), latency.grade());
+
+        let latency = LoadedLatency { idle_millis: Some(20), download_millis: Some(10), upload_millis: None };
+        assert_eq!(Some(0), latency.download_increase());
+        assert_eq!(Some(Grade::APlus), latency.grade());
+
+        let latency = LoadedLatency { idle_millis: None, download_millis: Some(45), upload_millis: None };
+        assert_eq!(None, latency.download_increase());
+        assert_eq!(None, latency.grade());
+
+        assert_eq!(None, LoadedLatency::default().grade());
+    }
+}
@@ -0,0 +1,420 @@
+use std::env;
+use std::net::IpAddr;
+use std::time;
+
+use hyper::client::{Client, ProxyConfig, RedirectPolicy};
+use hyper::client::pool::{Pool, Config};
+use hyper::header::{Headers, UserAgent};
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use url::Url;
+
+use connector::{TimeoutConnector, IpVersion};
+use pool::DEFAULT_CONNECTIONS;
+use ookla::Transport;
+use progress::Progress;
+
+const HTTP_SCHEME: &'static str = "http";
+const HTTPS_SCHEME: &'static str = "https";
+const DEFAULT_USER_AGENT: &'static str = "Hyper-speedtest";
+const DEFAULT_PROXY_PORT: u16 = 8080;
+const MAX_RETRY_DELAY_SECS: u64 = 60;
+
+
+/// Timeouts and retry policy, defaults suit a typical broadband link. Slow links need
+/// longer timeouts and cutoff, fast LANs can use shorter ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkOptions {
+    pub connect_timeout: time::Duration,
+    /// Read/write timeout of config, server list, ping and download requests
+    pub io_timeout: time::Duration,
+    pub upload_io_timeout: time::Duration,
+    /// Download test stops reading after this long
+    pub download_cutoff: time::Duration,
+    /// Download/upload test stops once this many bytes have been transferred
+    pub max_bytes: Option<u64>,
+    /// Number of times fetching speedtest config is retried
+    pub retries: u64,
+    /// Delay before first retry, doubled on every further retry
+    pub retry_delay: time::Duration
+}
+
+
+impl Default for NetworkOptions {
+    fn default() -> NetworkOptions {
+        NetworkOptions {
+            connect_timeout: time::Duration::from_secs(30),
+            io_timeout: time::Duration::from_secs(10),
+            upload_io_timeout: time::Duration::from_secs(5),
+            download_cutoff: time::Duration::from_secs(10),
+            max_bytes: None,
+            retries: 10,
+            retry_delay: time::Duration::from_secs(1)
+        }
+    }
+}
+
+
+impl NetworkOptions {
+    /// Whether given number of transferred bytes uses up max_bytes
+    pub fn reached_max_bytes(&self, bytes: u64) -> bool {
+        self.max_bytes.map_or(false, |max| bytes >= max)
+    }
+
+    /// Exponential backoff - delay before retry number `attempt` (starting at 0), capped
+    /// at a minute.
+    pub fn retry_delay_for(&self, attempt: u64) -> time::Duration {
+        let max_delay = time::Duration::from_secs(MAX_RETRY_DELAY_SECS);
+        let factor = if attempt >= 16 { 1 << 16 } else { 1 << attempt };
+        match self.retry_delay.checked_mul(factor) {
+            Some(delay) if delay <= max_delay   => delay,
+            _                                   => max_delay
+        }
+    }
+}
+
+
+/// How latency to a server is measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PingMethod {
+    /// Time taken to fetch latency.txt, includes HTTP overhead and server processing
+    Http,
+    /// TCP handshake time to the host:port of the server
+    Tcp
+}
+
+
+impl PingMethod {
+    pub fn parse(method: &str) -> Result<PingMethod, String> {
+        match method.to_lowercase().as_str() {
+            "http"  => Ok(PingMethod::Http),
+            "tcp"   => Ok(PingMethod::Tcp),
+            _       => Err(format!("Invalid ping method '{}', it should be http or tcp", method))
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match *self {
+            PingMethod::Http    => "http",
+            PingMethod::Tcp     => "tcp"
+        }
+    }
+}
+
+
+impl Default for PingMethod {
+    fn default() -> PingMethod {
+        PingMethod::Http
+    }
+}
+
+
+/// Options shared by every HTTP client created for config, server list and test
+/// requests.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    pub https: bool,
+    /// Proxy as host:port or http://host:port, HTTP_PROXY/HTTPS_PROXY are used when not set
+    pub proxy: Option<String>,
+    /// Hosts that should not go through proxy, added to the ones in NO_PROXY
+    pub no_proxy: Vec<String>,
+    pub user_agent: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Local address every connection is bound to
+    pub source_address: Option<IpAddr>,
+    /// Network interface every connection is bound to (linux only)
+    pub interface: Option<String>,
+    pub ip_version: IpVersion,
+    /// Persistent connections used per test phase, 0 uses DEFAULT_CONNECTIONS
+    pub connections: usize,
+    pub network: NetworkOptions,
+    pub ping_method: PingMethod,
+    pub transport: Transport,
+    /// Where tests report progress to, shared by all clones of these options
+    pub progress: Progress
+}
+
+
+impl ClientOptions {
+    pub fn scheme(&self) -> &'static str {
+        if self.https { HTTPS_SCHEME } else { HTTP_SCHEME }
+    }
+
+    /// Rewrites given url to use scheme of these options, urls in the speedtest
+    /// server list are all plain http.
+    pub fn with_scheme(&self, url: &str) -> String {
+        match url.find("://") {
+            Some(idx)   => format!("{}{}", self.scheme(), &url[idx..]),
+            None        => format!("{}://{}", self.scheme(), url)
+        }
+    }
+
+    /// Which source address/interface tests were run from, empty when not bound
+    pub fn source(&self) -> String {
+        match (&self.interface, &self.source_address) {
+            (&Some(ref i), &Some(ref a))    => format!("{} ({})", i, a),
+            (&Some(ref i), &None)           => i.clone(),
+            (&None, &Some(ref a))           => a.to_string(),
+            (&None, &None)                  => String::new()
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        if self.connections == 0 { DEFAULT_CONNECTIONS } else { self.connections }
+    }
+
+    /// Connector for every TCP connection made for these options
+    pub fn connector(&self) -> TimeoutConnector {
+        let mut connector = TimeoutConnector::new(self.network.connect_timeout);
+        connector.source_address = self.source_address;
+        connector.interface = self.interface.clone();
+        connector.ip_version = self.ip_version;
+        connector
+    }
+
+    /// Copy of these options restricted to given address family
+    pub fn with_ip_version(&self, ip_version: IpVersion) -> ClientOptions {
+        let mut options = self.clone();
+        options.ip_version = ip_version;
+        options
+    }
+
+    /// Proxy (host, port) to use for given url, if any.
+    pub fn proxy_for(&self, url: &str) -> Option<(String, u16)> {
+        let parsed_url = match Url::parse(url) {
+            Ok(u)   => u,
+            Err(_)  => return None
+        };
+
+        let proxy = match self.proxy {
+            Some(ref p) => Some(p.clone()),
+            None        => {
+                if parsed_url.scheme() == HTTPS_SCHEME {
+                    env_var(&["HTTPS_PROXY", "https_proxy"])
+                } else {
+                    env_var(&["HTTP_PROXY", "http_proxy"])
+                }
+            }
+        };
+
+        let mut no_proxy = self.no_proxy.clone();
+        if let Some(env_no_proxy) = env_var(&["NO_PROXY", "no_proxy"]) {
+            no_proxy.extend(env_no_proxy.split(',').map(|h| h.trim().to_string()));
+        }
+
+        match (proxy, parsed_url.host_str()) {
+            (Some(p), Some(host))   => {
+                if is_no_proxy_host(host, &no_proxy) {
+                    None
+                } else {
+                    parse_proxy(&p)
+                }
+            },
+            _                       => None
+        }
+    }
+}
+
+
+/// Client for given url that follows redirects with given read/write timeout. TLS is
+/// only set up when https is requested and proxy is used when configured for url.
+pub fn new_client(options: &ClientOptions, url: &str, io_timeout: time::Duration) -> Client {
+    build_client(options, url, io_timeout, None)
+}
+
+
+/// Same as `new_client` but connections are kept alive and reused by later requests,
+/// at most `max_idle` connections per host are kept open.
+pub fn new_pooled_client(options: &ClientOptions, url: &str, io_timeout: time::Duration,
+                         max_idle: usize) -> Client {
+    build_client(options, url, io_timeout, Some(Config { max_idle: max_idle }))
+}
+
+
+fn build_client(options: &ClientOptions, url: &str, io_timeout: time::Duration,
+                pool_config: Option<Config>) -> Client {
+    let connector = options.connector();
+
+    let mut client = match options.proxy_for(url) {
+        Some((host, port))  => {
+            let ssl = NativeTlsClient::new().expect("Unable to initialise TLS");
+            let mut proxy_config = ProxyConfig::new(HTTP_SCHEME, host, port, connector, ssl);
+            proxy_config.set_pool_config(pool_config);
+            Client::with_proxy_config(proxy_config)
+        },
+        None                => {
+            match (options.https, pool_config) {
+                (true, Some(config))    => {
+                    let ssl = NativeTlsClient::new().expect("Unable to initialise TLS");
+                    let https = HttpsConnector::with_connector(ssl, connector);
+                    Client::with_connector(Pool::with_connector(config, https))
+                },
+                (true, None)            => {
+                    let ssl = NativeTlsClient::new().expect("Unable to initialise TLS");
+                    Client::with_connector(HttpsConnector::with_connector(ssl, connector))
+                },
+                (false, Some(config))   => Client::with_connector(Pool::with_connector(config, connector)),
+                (false, None)           => Client::with_connector(connector)
+            }
+        }
+    };
+
+    client.set_read_timeout(Some(io_timeout));
+    client.set_write_timeout(Some(io_timeout));
+    client.set_redirect_policy(RedirectPolicy::FollowAll);
+    client
+}
+
+
+/// User-Agent and any custom headers sent with every request
+pub fn default_headers(options: &ClientOptions) -> Headers {
+    let mut headers = Headers::new();
+    let user_agent = options.user_agent.clone().unwrap_or(DEFAULT_USER_AGENT.to_owned());
+    headers.set(UserAgent(user_agent));
+    for &(ref name, ref value) in &options.headers {
+        headers.set_raw(name.clone(), vec![value.clone().into_bytes()]);
+    }
+    headers
+}
+
+
+/// Parses a header given on command line as "Name: value"
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.find(':') {
+        Some(idx) if idx > 0 => {
+            let name = header[..idx].trim().to_string();
+            let value = header[idx + 1..].trim().to_string();
+            Ok((name, value))
+        },
+        _ => Err(format!("Invalid header '{}', it should be given as 'Name: value'", header))
+    }
+}
+
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names.iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+}
+
+
+fn parse_proxy(proxy: &str) -> Option<(String, u16)> {
+    let proxy_url = if proxy.contains("://") {
+        proxy.to_string()
+    } else {
+        format!("{}://{}", HTTP_SCHEME, proxy)
+    };
+
+    match Url::parse(&proxy_url) {
+        Ok(u)   => {
+            let port = u.port().unwrap_or(DEFAULT_PROXY_PORT);
+            u.host_str().map(|h| (h.to_string(), port))
+        },
+        Err(_)  => None
+    }
+}
+
+
+/// NO_PROXY entries match the host itself or any of its subdomains, "*" matches all hosts
+fn is_no_proxy_host(host: &str, no_proxy: &[String]) -> bool {
+    let host = host.to_lowercase();
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim().trim_start_matches('.').to_lowercase();
+        !entry.is_empty() &&
+            (entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientOptions, NetworkOptions, PingMethod, parse_header, parse_proxy, is_no_proxy_host, default_headers};
+    use std::time::Duration;
+
+    #[test]
+    fn with_scheme_test() {
+        let http = ClientOptions { https: false, ..ClientOptions::default() };
+        let https = ClientOptions { https: true, ..ClientOptions::default() };
+        let url = "http://speedtest.example.com/speedtest/upload.php";
+        assert_eq!(url, http.with_scheme(url));
+        assert_eq!("https://speedtest.example.com/speedtest/upload.php", https.with_scheme(url));
+        assert_eq!("https://speedtest.example.com", https.with_scheme("speedtest.example.com"));
+    }
+
+    #[test]
+    fn source_test() {
+        let mut options = ClientOptions::default();
+        assert_eq!("", options.source());
+        options.interface = Some("wwan0".to_string());
+        assert_eq!("wwan0", options.source());
+        options.source_address = Some("10.0.0.2".parse().unwrap());
+        assert_eq!("wwan0 (10.0.0.2)", options.source());
+    }
+
+    #[test]
+    fn parse_proxy_test() {
+        assert_eq!(Some(("proxy.local".to_string(), 3128)), parse_proxy("proxy.local:3128"));
+        assert_eq!(Some(("proxy.local".to_string(), 3128)), parse_proxy("http://proxy.local:3128/"));
+        assert_eq!(Some(("proxy.local".to_string(), 8080)), parse_proxy("proxy.local"));
+    }
+
+    #[test]
+    fn no_proxy_host_test() {
+        let no_proxy = vec!["example.com".to_string(), ".internal".to_string()];
+        assert!(is_no_proxy_host("example.com", &no_proxy));
+        assert!(is_no_proxy_host("speedtest.example.com", &no_proxy));
+        assert!(is_no_proxy_host("speedtest.internal", &no_proxy));
+        assert!(!is_no_proxy_host("notexample.com", &no_proxy));
+        assert!(is_no_proxy_host("anything.net", &vec!["*".to_string()]));
+    }
+
+    #[test]
+    fn proxy_for_test() {
+        let options = ClientOptions {
+            proxy: Some("proxy.local:3128".to_string()),
+            no_proxy: vec!["speedtest.internal".to_string()],
+            ..ClientOptions::default()
+        };
+        assert_eq!(Some(("proxy.local".to_string(), 3128)),
+                   options.proxy_for("http://www.speedtest.net/speedtest-config.php"));
+        assert_eq!(None, options.proxy_for("http://speedtest.internal/speedtest/latency.txt"));
+    }
+
+    #[test]
+    fn parse_header_test() {
+        assert_eq!(Ok(("X-Probe".to_string(), "office-1".to_string())), parse_header("X-Probe: office-1"));
+        assert!(parse_header("no separator").is_err());
+        assert!(parse_header(": value").is_err());
+    }
+
+    #[test]
+    fn default_headers_test() {
+        let options = ClientOptions {
+            user_agent: Some("probe/1.0".to_string()),
+            headers: vec![("X-Probe".to_string(), "office-1".to_string())],
+            ..ClientOptions::default()
+        };
+        let headers = default_headers(&options);
+        assert_eq!(Some("probe/1.0".to_string()), headers.get_raw("User-Agent").map(|v| String::from_utf8_lossy(&v[0]).into_owned()));
+        assert_eq!(Some("office-1".to_string()), headers.get_raw("X-Probe").map(|v| String::from_utf8_lossy(&v[0]).into_owned()));
+    }
+
+    #[test]
+    fn retry_delay_backoff_test() {
+        let network = NetworkOptions { retry_delay: Duration::from_millis(500), ..NetworkOptions::default() };
+        assert_eq!(Duration::from_millis(500), network.retry_delay_for(0));
+        assert_eq!(Duration::from_millis(2000), network.retry_delay_for(2));
+        assert_eq!(Duration::from_secs(60), network.retry_delay_for(10));
+        assert_eq!(Duration::from_secs(60), network.retry_delay_for(100));
+
+        let network = NetworkOptions { retry_delay: Duration::from_millis(u64::max_value()), ..NetworkOptions::default() };
+        assert_eq!(Duration::from_secs(60), network.retry_delay_for(16));
+    }
+
+    #[test]
+    fn parse_ping_method_test() {
+        assert_eq!(Ok(PingMethod::Http), PingMethod::parse("http"));
+        assert_eq!(Ok(PingMethod::Tcp), PingMethod::parse("TCP"));
+        assert!(PingMethod::parse("icmp").is_err());
+    }
+}
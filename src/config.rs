@@ -1,18 +1,19 @@
 use std::io::Read;
 use std::io::Cursor;
+use std::fs::File;
+use std::thread;
 use std::error::Error;
 use std::collections::HashMap;
-use std::time;
 
 use xml::reader::{EventReader, XmlEvent};
 use xml::attribute::OwnedAttribute;
 use hyper;
-use hyper::client::Client;
 use hyper::client::response::Response;
-use hyper::client::RedirectPolicy;
 use hyper::client::Body;
-use hyper::header::{Headers, UserAgent, Header, ContentLength};
-use hyper_timeout_connector::HttpTimeoutConnector;
+use hyper::header::{Header, ContentLength};
+
+use client::{ClientOptions, new_client, default_headers};
+use logging;
 
 pub trait GenerateConfig<T> {
     fn from_xml(&Vec<OwnedAttribute>) -> T;
@@ -28,76 +29,118 @@ pub struct FullConfig {
 }
 
 impl FullConfig {
-    pub fn new() -> FullConfig {
-        let url = "http://www.speedtest.net/speedtest-config.php";
-        let mut connector = HttpTimeoutConnector::new();
-        connector.set_connect_timeout(Some(time::Duration::from_secs(30)));
+    /// Fetches config, retrying with exponential backoff when it cannot be parsed. The
+    /// speed test config request returns nothing sometimes, but it looks like a glitch on
+    /// the server side as similar content-length:0 responses come back when queried using
+    /// curl as well.
+    pub fn fetch(options: &ClientOptions) -> FullConfig {
+        let mut config = FullConfig::new(options);
+        let mut attempt = 0;
 
-        let mut client = Client::with_connector(connector);
-        client.set_read_timeout(Some(time::Duration::from_secs(10)));
-        client.set_write_timeout(Some(time::Duration::from_secs(10)));
+        while !config.parsing_succeeded && attempt < options.network.retries {
+            let delay = options.network.retry_delay_for(attempt);
+            chatter!("Retrying in {}ms...", delay.as_secs() * 1_000 + (delay.subsec_nanos() / 1_000_000) as u64);
+            thread::sleep(delay);
+            config = FullConfig::new(options);
+            attempt += 1;
+        }
+        config
+    }
 
-        client.set_redirect_policy(RedirectPolicy::FollowAll);
-        let mut headers = Headers::new();
-        headers.set(UserAgent("Hyper-speedtest".to_owned()));
+    pub fn new(options: &ClientOptions) -> FullConfig {
+        let url = format!("{}://www.speedtest.net/speedtest-config.php", options.scheme());
+        let client = new_client(options, url.as_str(), options.network.io_timeout);
 
-        let mut response = client.get(url)
-                            .headers(headers)
+        let response = client.get(url.as_str())
+                            .headers(default_headers(options))
                             .send();
-        let mut full_config = FullConfig::default();
-        full_config.parsing_succeeded = false;
 
         match response {
             Ok(res)    => {
                 let all_headers_wrapped = &res.headers.to_owned();
                 let default_content_len = ContentLength(0);
                 let content_length: &ContentLength = all_headers_wrapped.get().unwrap_or(&default_content_len);
-    //            println!("{:?}", content_length);
                 let no_content_length: u64 = 0;
                 if res.status == hyper::Ok && content_length.0 > no_content_length {
+                    FullConfig::from_reader(res)
 
-                    let parser = EventReader::new(res);
-
-                    for e in parser {
-                        match e {
-                            Ok(XmlEvent::StartElement { name, attributes, .. }) => {
-                                if name.to_string() == "client".to_string() {
-                                    let client_config = build_config::<ClientConfig>(ClientConfig::default(), &attributes);
-                                    full_config.client = client_config;
+                } else {
+                    warn!("{} returned {} with content length {}", url, res.status, content_length.0);
+                    FullConfig::default()
+                }
+            },
+            Err(e)      => {
+                if logging::is_hyper_timeout(&e) {
+                    warn!("{} timed out", url);
+                } else {
+                    warn!("Cannot fetch {} - {}", url, e);
+                }
+                FullConfig::default()
+            }
+        }
+    }
 
-                                } else if name.to_string() == "server-config".to_string() {
-                                    let server_config = build_config::<ServerConfig>(ServerConfig::default(), &attributes);
-                                    full_config.server = server_config;
+    /// Parses speedtest-config.php XML, parsing only succeeds when the XML is well formed
+    /// and has upload settings.
+    pub fn from_reader<R: Read>(reader: R) -> FullConfig {
+        let mut full_config = FullConfig::default();
+        let mut found_upload = false;
+        let mut xml_error = false;
+        let parser = EventReader::new(reader);
 
-                                } else if name.to_string() == "download".to_string() {
-                                    let download_config = build_config::<DownloadConfig>(DownloadConfig::default(), &attributes);
-                                    full_config.download = download_config;
+        for e in parser {
+            match e {
+                Ok(XmlEvent::StartElement { name, attributes, .. }) => {
+                    if name.to_string() == "client".to_string() {
+                        let client_config = build_config::<ClientConfig>(ClientConfig::default(), &attributes);
+                        full_config.client = client_config;
 
-                                } else if name.to_string() == "upload".to_string() {
-                                    let upload_config = build_config::<UploadConfig>(UploadConfig::default(), &attributes);
-                                    full_config.upload = upload_config;
-                                }
-                            }
-                            Err(e) => {
-    //                            println!("Error parsing configuration XML");
-                            }
-                            _ => {}
-                        }
-                    }
-                    full_config.parsing_succeeded = true;
-    //                println!("{:?}", full_config);
+                    } else if name.to_string() == "server-config".to_string() {
+                        let server_config = build_config::<ServerConfig>(ServerConfig::default(), &attributes);
+                        full_config.server = server_config;
 
-                } else {
+                    } else if name.to_string() == "download".to_string() {
+                        let download_config = build_config::<DownloadConfig>(DownloadConfig::default(), &attributes);
+                        full_config.download = download_config;
 
-    //                println!("Cannot retrieve config data from server");
+                    } else if name.to_string() == "upload".to_string() {
+                        let upload_config = build_config::<UploadConfig>(UploadConfig::default(), &attributes);
+                        full_config.upload = upload_config;
+                        found_upload = true;
+                    }
                 }
-            },
-            Err(e)      => {
-    //            println!("Error fetching config file - please try again");
+                Err(e) => {
+                    warn!("Cannot parse speedtest config XML - {}", e);
+                    xml_error = true;
+                    break;
+                }
+                _ => {}
             }
         }
+        full_config.parsing_succeeded = found_upload && !xml_error;
+        if !found_upload && !xml_error {
+            warn!("Speedtest config has no upload settings");
+        }
+        debug!("{:?}", full_config);
         full_config
     }
+
+    /// Config saved from speedtest-config.php earlier
+    pub fn from_file(path: &str) -> Result<FullConfig, String> {
+        let file = File::open(path).map_err(|e| format!("Cannot open speedtest config {} - {}", path, e))?;
+        let config = FullConfig::from_reader(file);
+        if config.parsing_succeeded {
+            Ok(config)
+        } else {
+            Err(format!("Cannot parse speedtest config {}", path))
+        }
+    }
+
+    /// Built-in config with the usual speedtest.net settings, client details (address,
+    /// ISP and location) are unknown.
+    pub fn default_config() -> FullConfig {
+        FullConfig::from_reader(Cursor::new(DEFAULT_CONFIG_XML))
+    }
 }
 
 
@@ -308,7 +351,7 @@ impl GenerateConfig<UploadConfig> for UploadConfig {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct TestServerConfig {
     pub url: String,
     pub latitude: f32,
@@ -322,56 +365,45 @@ pub struct TestServerConfig {
 }
 
 
-pub fn get_all_test_servers(use_cached: bool) -> Vec<TestServerConfig> {
+pub fn get_all_test_servers(use_cached: bool, options: &ClientOptions) -> Vec<TestServerConfig> {
     if use_cached {
         get_all_test_servers_from_file()
 
     } else {
-        get_all_test_servers_from_server()
+        get_all_test_servers_from_server(options)
     }
 
 }
 
 
 pub fn get_all_test_servers_from_file() -> Vec<TestServerConfig> {
-    let mut c = Cursor::new(CACHED_XML_CONTENT);
-//    let mut s = String::new();
-//    let read = c.read_to_string(&mut s);
-//    println!("{:?}", s);
+    let c = Cursor::new(CACHED_XML_CONTENT);
     parse_test_server_xml(c)
 }
 
 
-pub fn get_all_test_servers_from_server() -> Vec<TestServerConfig> {
+pub fn get_all_test_servers_from_server(options: &ClientOptions) -> Vec<TestServerConfig> {
     let urls = vec![
-        "http://www.speedtest.net/speedtest-servers-static.php",
-        "http://c.speedtest.net/speedtest-servers-static.php",
-        "http://www.speedtest.net/speedtest-servers.php",
-        "http://c.speedtest.net/speedtest-servers.php"
+        "www.speedtest.net/speedtest-servers-static.php",
+        "c.speedtest.net/speedtest-servers-static.php",
+        "www.speedtest.net/speedtest-servers.php",
+        "c.speedtest.net/speedtest-servers.php"
     ];
 
     let mut all_test_servers: Vec<TestServerConfig> = Vec::new();
 
     for url in urls {
-        let mut connector = HttpTimeoutConnector::new();
-        connector.set_connect_timeout(Some(time::Duration::from_secs(30)));
-
-        let mut client = Client::with_connector(connector);
-        client.set_read_timeout(Some(time::Duration::from_secs(10)));
-        client.set_write_timeout(Some(time::Duration::from_secs(10)));
-        client.set_redirect_policy(RedirectPolicy::FollowAll);
-
-        let mut headers = Headers::new();
-        headers.set(UserAgent("Hyper-speedtest".to_owned()));
-        let mut response = client.get(url)
-                                .headers(headers)
+        let full_url = options.with_scheme(url);
+        let client = new_client(options, full_url.as_str(), options.network.io_timeout);
+        let response = client.get(full_url.as_str())
+                                .headers(default_headers(options))
                                 .send();
 
         match response {
             Ok(res)    => {
                 let all_headers_wrapped = &res.headers.to_owned();
-                let content_length: &ContentLength = all_headers_wrapped.get().unwrap();
-//                println!("{:?}", content_length);
+                let default_content_len = ContentLength(0);
+                let content_length: &ContentLength = all_headers_wrapped.get().unwrap_or(&default_content_len);
                 let no_content_length: u64 = 0;
                 if res.status == hyper::Ok && content_length.0 > no_content_length {
                     return parse_test_server_xml(res);
@@ -379,8 +411,15 @@ pub fn get_all_test_servers_from_server() -> Vec<TestServerConfig> {
                     break;
 
                 }
+                warn!("{} returned {} with content length {}", full_url, res.status, content_length.0);
             },
-            Err(e)      => {}
+            Err(e)      => {
+                if logging::is_hyper_timeout(&e) {
+                    warn!("{} timed out", full_url);
+                } else {
+                    warn!("Cannot fetch {} - {}", full_url, e);
+                }
+            }
         }
     }
     all_test_servers
@@ -398,7 +437,6 @@ fn parse_test_server_xml<T: Read>(res: T) -> Vec<TestServerConfig> {
     for e in parser {
         match e {
             Ok(XmlEvent::StartElement { name, attributes, .. }) => {
-//                println!("{:?}", name.to_string());
                 if name.to_string() == "server".to_string() {
                     let mut url: String = String::new();
                     let mut latitude: f32 = 0.0;
@@ -466,7 +504,7 @@ fn parse_test_server_xml<T: Read>(res: T) -> Vec<TestServerConfig> {
                 }
             }
             Err(e) => {
-//                println!("Error parsing configuration XML {:?}", e);
+                warn!("Cannot parse server list XML - {}", e);
                 continue;
             }
             _ => {}
@@ -483,6 +521,17 @@ pub fn find_ignore_ids(ids_str: String) -> Vec<u64> {
 }
 
 
+/// Used when speedtest-config.php cannot be fetched
+const DEFAULT_CONFIG_XML: &'static str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<settings>
+<client ip="" lat="0" lon="0" isp="Unknown" isprating="3.7" rating="0" ispdlavg="0" ispulavg="0" loggedin="0" country="" />
+<server-config threadcount="4" ignoreids="" notonmap="" forcepingid="" preferredserverid=""/>
+<download testlength="10" initialtest="250K" mintestsize="250K" threadsperurl="4"/>
+<upload testlength="10" ratio="5" initialtest="0" mintestsize="32K" threads="2" maxchunksize="512K" maxchunkcount="50" threadsperurl="4"/>
+<latency testlength="10" waittime="50" timeout="20"/>
+</settings>
+"#;
+
 const CACHED_XML_CONTENT: &'static str = r#"
 <?xml version="1.0" encoding="UTF-8"?>
 <settings>
@@ -5876,4 +5925,32 @@ const CACHED_XML_CONTENT: &'static str = r#"
 <server url="http://speedtest1.tvfuego.com/speedtest/upload.php" lat="-53.7860" lon="-67.7002" name="Rio Grande" country="Argentina" cc="AR" sponsor="TV Fuego S.A." id="9531"  url2="http://speedtest2.tvfuego.com/speedtest/upload.php" host="speedtest1.tvfuego.com:8080" />
 </servers>
 </settings>
-"#;
\ No newline at end of file
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::FullConfig;
+    use std::io::Cursor;
+
+    #[test]
+    fn default_config_test() {
+        let config = FullConfig::default_config();
+        assert!(config.parsing_succeeded);
+        assert_eq!(10, config.upload.testlength);
+        assert_eq!(5, config.upload.ratio);
+        assert_eq!(50, config.upload.maxchunkcount);
+        assert_eq!(4, config.download.threadsperurl);
+    }
+
+    #[test]
+    fn incomplete_config_test() {
+        assert!(!FullConfig::from_reader(Cursor::new("")).parsing_succeeded);
+        assert!(!FullConfig::from_reader(Cursor::new("<settings><client ip=\"1.2.3.4\"/></settings>")).parsing_succeeded);
+        assert!(!FullConfig::from_reader(Cursor::new("<settings><upload testlength=")).parsing_succeeded);
+    }
+
+    #[test]
+    fn missing_file_test() {
+        assert!(FullConfig::from_file("/nonexistent/speedtest-config.xml").is_err());
+    }
+}
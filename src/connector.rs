@@ -0,0 +1,178 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{NetworkConnector, HttpStream};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+
+/// Address family connections are restricted to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6
+}
+
+
+impl IpVersion {
+    pub fn label(&self) -> &'static str {
+        match *self {
+            IpVersion::Any  => "",
+            IpVersion::V4   => "IPv4",
+            IpVersion::V6   => "IPv6"
+        }
+    }
+}
+
+
+impl Default for IpVersion {
+    fn default() -> IpVersion {
+        IpVersion::Any
+    }
+}
+
+
+/// Plain TCP connector with a connect timeout that can bind outgoing connections to a
+/// source address and/or network interface, so each uplink of a multi-homed host can
+/// be tested on its own.
+#[derive(Debug, Clone)]
+pub struct TimeoutConnector {
+    pub connect_timeout: Duration,
+    pub source_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub ip_version: IpVersion
+}
+
+
+impl TimeoutConnector {
+    pub fn new(connect_timeout: Duration) -> TimeoutConnector {
+        TimeoutConnector {
+            connect_timeout: connect_timeout,
+            source_address: None,
+            interface: None,
+            ip_version: IpVersion::Any
+        }
+    }
+
+    /// Connects to first reachable address of host, only addresses of the requested
+    /// family and of the same family as source address (when set) are tried.
+    pub fn connect_tcp(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        // IPv6 hosts come in as [::1] from urls
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?
+            .filter(|addr| self.is_usable_address(addr))
+            .collect();
+
+        let mut last_error = Error::new(ErrorKind::Other,
+                                        format!("No usable address found for {}", host));
+        for addr in addrs {
+            match self.connect_addr(&addr) {
+                Ok(stream)  => return Ok(stream),
+                Err(e)      => last_error = e
+            }
+        }
+        Err(last_error)
+    }
+
+    fn is_usable_address(&self, addr: &SocketAddr) -> bool {
+        let matches_source = match self.source_address {
+            Some(IpAddr::V4(_)) => addr.is_ipv4(),
+            Some(IpAddr::V6(_)) => addr.is_ipv6(),
+            None                => true
+        };
+
+        let matches_version = match self.ip_version {
+            IpVersion::V4   => addr.is_ipv4(),
+            IpVersion::V6   => addr.is_ipv6(),
+            IpVersion::Any  => true
+        };
+
+        matches_source && matches_version
+    }
+
+    fn connect_addr(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+        if let Some(ref interface) = self.interface {
+            bind_to_interface(&socket, interface)?;
+        }
+
+        if let Some(source) = self.source_address {
+            socket.bind(&SockAddr::from(SocketAddr::new(source, 0)))?;
+        }
+
+        socket.connect_timeout(&SockAddr::from(*addr), self.connect_timeout)?;
+        Ok(socket.into_tcp_stream())
+    }
+}
+
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        match scheme {
+            "http" | "https" => Ok(HttpStream(self.connect_tcp(host, port)?)),
+            _                => Err(Error::new(ErrorKind::InvalidInput, "Invalid scheme for Http").into())
+        }
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+fn bind_to_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    use std::ffi::CString;
+
+    let name = CString::new(interface)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid interface name"))?;
+    socket.bind_device(Some(&name))
+}
+
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_interface(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(Error::new(ErrorKind::Other, "Binding to an interface is only supported on linux, use a source address instead"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{TimeoutConnector, IpVersion};
+    use std::net::{IpAddr, TcpListener};
+    use std::time::Duration;
+
+    #[test]
+    fn connect_from_source_address_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut connector = TimeoutConnector::new(Duration::from_secs(5));
+        connector.source_address = Some("127.0.0.1".parse::<IpAddr>().unwrap());
+        let stream = connector.connect_tcp("localhost", port).unwrap();
+        assert_eq!("127.0.0.1".parse::<IpAddr>().unwrap(), stream.local_addr().unwrap().ip());
+    }
+
+    #[test]
+    fn no_address_of_source_family_test() {
+        let mut connector = TimeoutConnector::new(Duration::from_secs(5));
+        connector.source_address = Some("::1".parse::<IpAddr>().unwrap());
+        assert!(connector.connect_tcp("127.0.0.1", 80).is_err());
+    }
+
+    #[test]
+    fn ip_version_restricts_addresses_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut connector = TimeoutConnector::new(Duration::from_secs(5));
+        connector.ip_version = IpVersion::V6;
+        assert!(connector.connect_tcp("127.0.0.1", port).is_err());
+
+        connector.ip_version = IpVersion::V4;
+        assert!(connector.connect_tcp("127.0.0.1", port).is_ok());
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use hyper::header::{AccessControlAllowOrigin, ContentType, Location};
+use hyper::method::Method;
+use hyper::server::{Handler, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::json::{Json, ToJson};
+use url::form_urlencoded;
+
+use client::PingMethod;
+use history::{HistoryStore, HistoryFilter};
+use ookla::Transport;
+use results;
+use results::RunSummary;
+use runner;
+use runner::{RunOptions, RunOutcome, ServerStrategy};
+use time_utils::get_current_utc_time_as_string;
+use units::SpeedFormat;
+
+pub const DEFAULT_LISTEN_ADDRESS: &'static str = "127.0.0.1:8000";
+/// Finished jobs beyond this many are forgotten, oldest first
+const MAX_JOBS: usize = 100;
+/// Queued and running jobs beyond this many are turned away with 503
+const MAX_PENDING_JOBS: usize = 5;
+const MAX_TESTS_PER_REQUEST: u64 = 10;
+const MAX_COMPARED_SERVERS: u64 = 10;
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed
+}
+
+
+impl JobStatus {
+    pub fn label(&self) -> &'static str {
+        match *self {
+            JobStatus::Queued   => "queued",
+            JobStatus::Running  => "running",
+            JobStatus::Finished => "finished",
+            JobStatus::Failed   => "failed"
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        *self == JobStatus::Finished || *self == JobStatus::Failed
+    }
+}
+
+
+/// Run requested with `POST /tests`
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub status: JobStatus,
+    pub requested_at: String,
+    pub outcome: Option<RunOutcome>,
+    pub error: Option<String>,
+    /// Speeds of the outcome are reported in the unit of the run
+    pub speed_format: SpeedFormat
+}
+
+
+impl ToJson for Job {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("id".to_string(), self.id.to_json());
+        obj.insert("status".to_string(), self.status.label().to_json());
+        obj.insert("requested_at".to_string(), self.requested_at.to_json());
+        if let Some(ref outcome) = self.outcome {
+            obj.insert("started_at".to_string(), outcome.started_at.to_json());
+            obj.insert("tests".to_string(), Json::Array(outcome.results.iter()
+                .map(|r| r.to_json_with(&self.speed_format))
+                .collect()));
+            obj.insert("summary".to_string(), outcome.summary.to_json_with(&self.speed_format));
+        }
+        if let Some(ref error) = self.error {
+            obj.insert("error".to_string(), error.to_json());
+        }
+        Json::Object(obj)
+    }
+}
+
+
+/// Request handled by the daemon, see `route`
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    SubmitTest,
+    TestStatus(u64),
+    Results,
+    Preflight,
+    MethodNotAllowed,
+    NotFound
+}
+
+
+/// Maps method and path (without query string) to a route
+pub fn route(method: &Method, path: &str) -> Route {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let found = match segments.as_slice() {
+        ["tests"]           => Some((Route::SubmitTest, Method::Post)),
+        ["tests", id]       => match id.parse::<u64>() {
+            Ok(id)  => Some((Route::TestStatus(id), Method::Get)),
+            Err(_)  => None
+        },
+        ["results"]         => Some((Route::Results, Method::Get)),
+        _                   => None
+    };
+    match found {
+        Some((r, ref allowed)) if allowed == method => r,
+        Some(_) if *method == Method::Options       => Route::Preflight,
+        Some(_)                                     => Route::MethodNotAllowed,
+        None                                        => Route::NotFound
+    }
+}
+
+
+/// Options of a `POST /tests` body applied over the daemon defaults. An empty body runs
+/// with the defaults, unknown keys are rejected so typos do not go unnoticed.
+pub fn parse_test_request(body: &str, defaults: &RunOptions) -> Result<RunOptions, String> {
+    let mut options = defaults.clone();
+    if body.trim().is_empty() {
+        return Ok(options);
+    }
+
+    let json = Json::from_str(body).map_err(|e| format!("Invalid JSON - {}", e))?;
+    let obj = match json.as_object() {
+        Some(o) => o,
+        None    => return Err("Request body should be a JSON object".to_string())
+    };
+
+    for (key, value) in obj {
+        let invalid = || format!("Invalid value {} for {}", value, key);
+        match key.as_str() {
+            "number_of_tests"       => {
+                let n = value.as_u64().ok_or_else(&invalid)?;
+                if n == 0 || n > MAX_TESTS_PER_REQUEST {
+                    return Err(format!("number_of_tests should be between 1 and {}", MAX_TESTS_PER_REQUEST));
+                }
+                options.number_of_tests = n;
+            },
+            "server_country"        => options.server_country = Some(value.as_string().ok_or_else(&invalid)?.to_string()),
+            "server_country_code"   => options.server_country_code = Some(value.as_string().ok_or_else(&invalid)?.to_string()),
+            "dual_stack"            => options.dual_stack = value.as_boolean().ok_or_else(&invalid)?,
+            "loaded_latency"        => options.loaded_latency = value.as_boolean().ok_or_else(&invalid)?,
+            "download"              => options.download = value.as_boolean().ok_or_else(&invalid)?,
+            "upload"                => options.upload = value.as_boolean().ok_or_else(&invalid)?,
+            "compare"               => match value.as_u64() {
+                Some(n) if n > 0 && n <= MAX_COMPARED_SERVERS   => options.compare = n as usize,
+                _   => return Err(format!("compare should be between 1 and {}", MAX_COMPARED_SERVERS))
+            },
+            "compare_ids"           => {
+                let ids = value.as_array().ok_or_else(&invalid)?;
+                if ids.len() > MAX_COMPARED_SERVERS as usize {
+                    return Err(format!("compare_ids should have at most {} ids", MAX_COMPARED_SERVERS));
+                }
+                options.compare_ids = ids.iter().map(|id| id.as_u64().ok_or_else(&invalid)).collect::<Result<Vec<u64>, String>>()?;
+            },
+            "server_strategy"       => options.server_strategy = ServerStrategy::parse(value.as_string().ok_or_else(&invalid)?)?,
+            "top_k"                 => match value.as_u64() {
+                Some(k) if k > 0 && k <= MAX_COMPARED_SERVERS   => options.top_k = k as usize,
+                _   => return Err(format!("top_k should be between 1 and {}", MAX_COMPARED_SERVERS))
+            },
+            "https"                 => options.client.https = value.as_boolean().ok_or_else(&invalid)?,
+            "connections"           => match value.as_u64() {
+                Some(n) if n > 0    => options.client.connections = n as usize,
+                _                   => return Err(invalid())
+            },
+            "max_bytes"             => match value.as_u64() {
+                Some(n) if n > 0    => options.client.network.max_bytes = Some(n),
+                _                   => return Err(invalid())
+            },
+            "ping_method"           => options.client.ping_method = PingMethod::parse(value.as_string().ok_or_else(&invalid)?)?,
+            "transport"             => options.client.transport = Transport::parse(value.as_string().ok_or_else(&invalid)?)?,
+            _                       => return Err(format!("Unknown option '{}'", key))
+        }
+    }
+
+    if options.server_country.is_some() && options.server_country_code.is_some() {
+        return Err("Only one of server_country and server_country_code can be given".to_string());
+    }
+    Ok(options)
+}
+
+
+/// `GET /results` query - history filter and whether CSV rather than JSON is wanted
+pub fn parse_results_query(query: &str) -> Result<(HistoryFilter, bool), String> {
+    let mut filter = HistoryFilter::default();
+    let mut csv = false;
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "from"      => filter.from_date = Some(value.into_owned()),
+            "to"        => filter.to_date = Some(value.into_owned()),
+            "server"    => filter.server = Some(value.into_owned()),
+            "limit"     => filter.limit = Some(value.parse::<u64>()
+                .map_err(|_| format!("Invalid limit '{}'", value))?),
+            "format"    => csv = match value.as_ref() {
+                "csv"   => true,
+                "json"  => false,
+                _       => return Err(format!("Invalid format '{}', it should be csv or json", value))
+            },
+            _           => return Err(format!("Unknown parameter '{}'", key))
+        }
+    }
+    Ok((filter, csv))
+}
+
+
+/// HTTP front end to runs - requested runs are queued and run one at a time on a
+/// separate thread, since concurrent runs would compete for the same link.
+pub struct Daemon {
+    defaults: RunOptions,
+    history_db: Option<String>,
+    allow_origin: Option<String>,
+    jobs: Arc<Mutex<BTreeMap<u64, Job>>>,
+    queue: Mutex<Sender<(u64, RunOptions)>>
+}
+
+
+impl Daemon {
+    /// `history_db` is where runs are recorded and `GET /results` reads from, None
+    /// disables both. `allow_origin` is sent as Access-Control-Allow-Origin so a web
+    /// page on that origin can call the API.
+    pub fn new(defaults: RunOptions, history_db: Option<String>, allow_origin: Option<String>) -> Daemon {
+        let jobs = Arc::new(Mutex::new(BTreeMap::new()));
+        let (queue_tx, queue_rx) = channel();
+
+        let runner_jobs = jobs.clone();
+        let runner_db = history_db.clone();
+        thread::spawn(move || run_jobs(queue_rx, runner_jobs, runner_db));
+
+        Daemon {
+            defaults: defaults,
+            history_db: history_db,
+            allow_origin: allow_origin,
+            jobs: jobs,
+            queue: Mutex::new(queue_tx)
+        }
+    }
+
+    /// Queues a run and returns the job id, fails with 503 when too many runs are
+    /// already queued or running
+    pub fn submit(&self, options: RunOptions) -> Result<u64, (StatusCode, String)> {
+        let internal = |e: &str| (StatusCode::InternalServerError, e.to_string());
+        let mut jobs = self.jobs.lock().map_err(|_| internal("Job list is not available"))?;
+        if pending_jobs(&jobs) >= MAX_PENDING_JOBS {
+            return Err((StatusCode::ServiceUnavailable,
+                        format!("{} tests are already queued or running, try again later", MAX_PENDING_JOBS)));
+        }
+        let id = jobs.keys().next_back().map(|id| id + 1).unwrap_or(1);
+        jobs.insert(id, Job {
+            id: id,
+            status: JobStatus::Queued,
+            requested_at: get_current_utc_time_as_string(),
+            outcome: None,
+            error: None,
+            speed_format: options.speed_format
+        });
+        forget_old_jobs(&mut jobs);
+
+        let queue = self.queue.lock().map_err(|_| internal("Job queue is not available"))?;
+        queue.send((id, options)).map_err(|_| internal("Job runner has stopped"))?;
+        Ok(id)
+    }
+
+    pub fn job(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().ok().and_then(|jobs| jobs.get(&id).cloned())
+    }
+
+    fn submit_test(&self, req: &mut Request) -> (StatusCode, Json) {
+        let mut body = String::new();
+        if req.by_ref().take(MAX_BODY_SIZE).read_to_string(&mut body).is_err() {
+            return error(StatusCode::BadRequest, "Cannot read request body");
+        }
+        let options = match parse_test_request(&body, &self.defaults) {
+            Ok(o)   => o,
+            Err(e)  => return error(StatusCode::BadRequest, &e)
+        };
+        match self.submit(options) {
+            Ok(id)  => match self.job(id) {
+                Some(job)   => (StatusCode::Accepted, job.to_json()),
+                None        => error(StatusCode::InternalServerError, "Job was lost")
+            },
+            Err((status, e))    => error(status, &e)
+        }
+    }
+
+    /// History as JSON (same document as --json) or CSV (same as --csv)
+    fn results(&self, query: &str) -> Result<(bool, String), (StatusCode, Json)> {
+        let (filter, csv) = parse_results_query(query).map_err(|e| error(StatusCode::BadRequest, &e))?;
+        let db_path = match self.history_db {
+            Some(ref p) => p,
+            None        => return Err(error(StatusCode::NotFound, "History is disabled"))
+        };
+        let found = HistoryStore::open(db_path).and_then(|store| store.query(&filter));
+        match found {
+            Ok(found)   => {
+                let summary = RunSummary::from_results(&found);
+                if csv {
+                    Ok((true, results::to_csv_string(&found, &summary, &self.defaults.speed_format)))
+                } else {
+                    Ok((false, results::to_json_string(&found, &summary, &self.defaults.speed_format)))
+                }
+            },
+            Err(e)      => Err(error(StatusCode::InternalServerError, &format!("Failed to query history - {}", e)))
+        }
+    }
+}
+
+
+impl Handler for Daemon {
+    fn handle(&self, mut req: Request, mut res: Response) {
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref p) => p.clone(),
+            _                               => String::new()
+        };
+        let (path, query) = match uri.find('?') {
+            Some(i) => (uri[..i].to_string(), uri[i + 1..].to_string()),
+            None    => (uri.clone(), String::new())
+        };
+        let method = req.method.clone();
+        chatter!("{} {}", method, uri);
+
+        if let Some(ref origin) = self.allow_origin {
+            res.headers_mut().set(AccessControlAllowOrigin::Value(origin.clone()));
+        }
+
+        let (status, json) = match route(&method, &path) {
+            Route::SubmitTest       => {
+                let (status, json) = self.submit_test(&mut req);
+                if let Some(id) = json.find("id").and_then(|id| id.as_u64()) {
+                    res.headers_mut().set(Location(format!("/tests/{}", id)));
+                }
+                (status, json)
+            },
+            Route::TestStatus(id)   => match self.job(id) {
+                Some(job)   => (StatusCode::Ok, job.to_json()),
+                None        => error(StatusCode::NotFound, &format!("Unknown test {}", id))
+            },
+            Route::Results          => match self.results(&query) {
+                Ok((csv, body))     => {
+                    let content_type = if csv { "text/csv" } else { "application/json" };
+                    res.headers_mut().set(ContentType(content_type.parse().unwrap()));
+                    res.send(body.as_bytes()).ok();
+                    return;
+                },
+                Err(e)              => e
+            },
+            Route::Preflight        => {
+                res.headers_mut().set_raw("Access-Control-Allow-Methods", vec![b"GET, POST".to_vec()]);
+                res.headers_mut().set_raw("Access-Control-Allow-Headers", vec![b"Content-Type".to_vec()]);
+                *res.status_mut() = StatusCode::NoContent;
+                return;
+            },
+            Route::MethodNotAllowed => error(StatusCode::MethodNotAllowed, "Method not allowed"),
+            Route::NotFound         => error(StatusCode::NotFound, "Not found")
+        };
+
+        *res.status_mut() = status;
+        res.headers_mut().set(ContentType::json());
+        res.send(format!("{}", json.pretty()).as_bytes()).ok();
+    }
+}
+
+
+/// Listens on `address` and serves until the process is stopped
+pub fn serve(address: &str, daemon: Daemon) -> Result<(), String> {
+    let server = Server::http(address).map_err(|e| format!("Cannot listen on {} - {}", address, e))?;
+    let listening = server.handle(daemon).map_err(|e| format!("Cannot listen on {} - {}", address, e))?;
+    chatter!("Listening on http://{}", listening.socket);
+    // Dropping Listening waits for the server threads, which never finish
+    Ok(())
+}
+
+
+fn run_jobs(queue: Receiver<(u64, RunOptions)>, jobs: Arc<Mutex<BTreeMap<u64, Job>>>, history_db: Option<String>) {
+    let update = |id: u64, f: &Fn(&mut Job)| {
+        if let Ok(mut jobs) = jobs.lock() {
+            if let Some(job) = jobs.get_mut(&id) {
+                f(job);
+            }
+        }
+    };
+
+    for (id, options) in queue.iter() {
+        update(id, &|job| job.status = JobStatus::Running);
+
+        let history = match history_db {
+            Some(ref path)  => match HistoryStore::open(path) {
+                Ok(store)   => Some(store),
+                Err(e)      => {
+                    warn!("Cannot open history database {}, results will not be stored - {}", path, e);
+                    None
+                }
+            },
+            None            => None
+        };
+
+        let outcome = runner::run(&options, history.as_ref());
+        update(id, &|job| match outcome {
+            Ok(ref o)   => {
+                job.status = JobStatus::Finished;
+                job.outcome = Some(o.clone());
+            },
+            Err(ref e)  => {
+                job.status = JobStatus::Failed;
+                job.error = Some(e.clone());
+            }
+        });
+    }
+}
+
+
+fn pending_jobs(jobs: &BTreeMap<u64, Job>) -> usize {
+    jobs.values().filter(|j| !j.status.is_done()).count()
+}
+
+
+fn forget_old_jobs(jobs: &mut BTreeMap<u64, Job>) {
+    while jobs.len() > MAX_JOBS {
+        let oldest_done = jobs.values().find(|j| j.status.is_done()).map(|j| j.id);
+        match oldest_done {
+            Some(id)    => { jobs.remove(&id); },
+            None        => break
+        }
+    }
+}
+
+
+fn error(status: StatusCode, message: &str) -> (StatusCode, Json) {
+    let mut obj = BTreeMap::new();
+    obj.insert("error".to_string(), message.to_json());
+    (status, Json::Object(obj))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Job, JobStatus, Route, route, parse_test_request, parse_results_query, pending_jobs,
+                forget_old_jobs, MAX_JOBS};
+    use client::PingMethod;
+    use hyper::method::Method;
+    use runner::RunOptions;
+    use std::collections::BTreeMap;
+    use units::SpeedFormat;
+
+    fn job(id: u64, status: JobStatus) -> Job {
+        Job {
+            id: id,
+            status: status,
+            requested_at: "2018-03-01 10:00:00".to_string(),
+            outcome: None,
+            error: None,
+            speed_format: SpeedFormat::default()
+        }
+    }
+
+    #[test]
+    fn route_test() {
+        assert_eq!(Route::SubmitTest, route(&Method::Post, "/tests"));
+        assert_eq!(Route::TestStatus(12), route(&Method::Get, "/tests/12/"));
+        assert_eq!(Route::Results, route(&Method::Get, "/results"));
+        assert_eq!(Route::Preflight, route(&Method::Options, "/tests"));
+        assert_eq!(Route::MethodNotAllowed, route(&Method::Get, "/tests"));
+        assert_eq!(Route::NotFound, route(&Method::Get, "/tests/abc"));
+        assert_eq!(Route::NotFound, route(&Method::Get, "/"));
+    }
+
+    #[test]
+    fn parse_test_request_test() {
+        let defaults = RunOptions::default();
+        let options = parse_test_request("", &defaults).unwrap();
+        assert_eq!(1, options.number_of_tests);
+
+        let body = r#"{"number_of_tests": 3, "server_country_code": "GB", "loaded_latency": false, "ping_method": "tcp"}"#;
+        let options = parse_test_request(body, &defaults).unwrap();
+        assert_eq!(3, options.number_of_tests);
+        assert_eq!(Some("GB".to_string()), options.server_country_code);
+        assert!(!options.loaded_latency);
+        assert_eq!(PingMethod::Tcp, options.client.ping_method);
+
+        let options = parse_test_request(r#"{"compare_ids": [1234, 5678]}"#, &defaults).unwrap();
+        assert_eq!(vec![1234, 5678], options.compare_ids);
+
+        let options = parse_test_request(r#"{"download": false, "upload": false}"#, &defaults).unwrap();
+        assert!(!options.download && !options.upload);
+
+        let options = parse_test_request(r#"{"max_bytes": 50000000}"#, &defaults).unwrap();
+        assert_eq!(Some(50000000), options.client.network.max_bytes);
+    }
+
+    #[test]
+    fn invalid_test_request_test() {
+        let defaults = RunOptions::default();
+        assert!(parse_test_request("[1]", &defaults).is_err());
+        assert!(parse_test_request(r#"{"number_of_tests": 0}"#, &defaults).is_err());
+        assert!(parse_test_request(r#"{"number_of_tests": "2"}"#, &defaults).is_err());
+        assert!(parse_test_request(r#"{"server": 1}"#, &defaults).is_err());
+        assert!(parse_test_request(r#"{"compare": 50}"#, &defaults).is_err());
+        assert!(parse_test_request(r#"{"server_country": "UK", "server_country_code": "GB"}"#, &defaults).is_err());
+    }
+
+    #[test]
+    fn pending_jobs_test() {
+        let mut jobs = BTreeMap::new();
+        for id in 1..(MAX_JOBS as u64 + 3) {
+            jobs.insert(id, job(id, JobStatus::Finished));
+        }
+        jobs.insert(200, job(200, JobStatus::Running));
+        jobs.insert(201, job(201, JobStatus::Queued));
+        assert_eq!(2, pending_jobs(&jobs));
+
+        forget_old_jobs(&mut jobs);
+        assert_eq!(MAX_JOBS, jobs.len());
+        assert_eq!(2, pending_jobs(&jobs));
+        assert!(!jobs.contains_key(&1));
+    }
+
+    #[test]
+    fn parse_results_query_test() {
+        let (filter, csv) = parse_results_query("format=csv&from=2018-01-01&server=Vodafone%20UK&limit=5").unwrap();
+        assert!(csv);
+        assert_eq!(Some("2018-01-01".to_string()), filter.from_date);
+        assert_eq!(Some("Vodafone UK".to_string()), filter.server);
+        assert_eq!(Some(5), filter.limit);
+
+        let (filter, csv) = parse_results_query("").unwrap();
+        assert!(!csv);
+        assert_eq!(None, filter.limit);
+
+        assert!(parse_results_query("format=xml").is_err());
+        assert!(parse_results_query("limit=all").is_err());
+    }
+}
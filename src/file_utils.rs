@@ -4,23 +4,39 @@ use std::io::prelude::*;
 
 pub fn write_to_file(csv_content: String, file_name: &str) -> () {
     let full_file_name = get_full_file_name(file_name);
+    write_content(csv_content, full_file_name);
+}
+
+
+pub fn write_json_to_file(json_content: String, file_name: &str) -> () {
+    let full_file_name = get_full_file_name_with_extension(file_name, "json");
+    write_content(json_content, full_file_name);
+}
+
+
+fn write_content(content: String, full_file_name: String) -> () {
     let mut f = File::create(full_file_name).expect("Unable to create file");
-    f.write_all(csv_content.as_bytes()).expect("Unable to write data to file");
+    f.write_all(content.as_bytes()).expect("Unable to write data to file");
 }
 
 
 fn get_full_file_name(file_name: &str) -> String {
-    if file_name.to_string().ends_with(".csv") {
+    get_full_file_name_with_extension(file_name, "csv")
+}
+
+
+fn get_full_file_name_with_extension(file_name: &str, extension: &str) -> String {
+    if file_name.to_string().ends_with(&format!(".{}", extension)) {
         return format!("{}", file_name);
 
     }
-    // if no csv in file name, return with csv extension
-    format!("{}.csv", file_name)
+    // if no extension in file name, return with given extension
+    format!("{}.{}", file_name, extension)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::get_full_file_name;
+    use super::{get_full_file_name, get_full_file_name_with_extension};
 
     #[test]
     fn get_full_file_name_no_csv_extension_test() {
@@ -31,4 +47,10 @@ mod tests {
     fn get_full_file_name_with_csv_extension_test() {
         assert_eq!("abc.csv".to_string(), get_full_file_name("abc.csv"));
     }
+
+    #[test]
+    fn get_full_file_name_json_extension_test() {
+        assert_eq!("abc.json".to_string(), get_full_file_name_with_extension("abc", "json"));
+        assert_eq!("abc.json".to_string(), get_full_file_name_with_extension("abc.json", "json"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,486 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use rusqlite::{Connection, Result, Row, NO_PARAMS};
+use rusqlite::types::ToSql;
+
+use results::TestResult;
+use stats::{mean, percentile};
+
+const HISTORY_DIR_NAME: &'static str = ".stest";
+const HISTORY_FILE_NAME: &'static str = "history.db";
+
+const CREATE_RESULTS_TABLE: &'static str = "
+    CREATE TABLE IF NOT EXISTS results (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_started_at  TEXT NOT NULL,
+        tested_at       TEXT NOT NULL,
+        test_number     INTEGER NOT NULL,
+        client_ip       TEXT NOT NULL,
+        client_isp      TEXT NOT NULL,
+        server_id       INTEGER NOT NULL,
+        server_name     TEXT NOT NULL,
+        server_country  TEXT NOT NULL,
+        server_url      TEXT NOT NULL,
+        latency_millis  INTEGER NOT NULL,
+        rx_start        TEXT NOT NULL,
+        rx_total_bytes  INTEGER NOT NULL,
+        rx_total_millis INTEGER NOT NULL,
+        rx_speed_mbps   REAL NOT NULL,
+        rx_end          TEXT NOT NULL,
+        tx_start        TEXT NOT NULL,
+        tx_total_bytes  INTEGER NOT NULL,
+        tx_total_millis INTEGER NOT NULL,
+        tx_speed_mbps   REAL NOT NULL,
+        tx_end          TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS results_tested_at ON results (tested_at);";
+
+const ADD_SOURCE_COLUMN: &'static str = "
+    ALTER TABLE results ADD COLUMN source TEXT NOT NULL DEFAULT '';";
+
+const ADD_IP_VERSION_COLUMN: &'static str = "
+    ALTER TABLE results ADD COLUMN ip_version TEXT NOT NULL DEFAULT '';";
+
+const ADD_LOADED_LATENCY_COLUMNS: &'static str = "
+    ALTER TABLE results ADD COLUMN rx_loaded_latency_millis INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE results ADD COLUMN tx_loaded_latency_millis INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE results ADD COLUMN bufferbloat_grade TEXT NOT NULL DEFAULT '';";
+
+const ADD_PING_METHOD_COLUMNS: &'static str = "
+    ALTER TABLE results ADD COLUMN http_latency_millis INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE results ADD COLUMN tcp_latency_millis INTEGER NOT NULL DEFAULT 0;";
+
+const ADD_SKIPPED_COLUMNS: &'static str = "
+    ALTER TABLE results ADD COLUMN rx_skipped INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE results ADD COLUMN tx_skipped INTEGER NOT NULL DEFAULT 0;";
+
+const ADD_PING_FAILED_COLUMN: &'static str = "
+    ALTER TABLE results ADD COLUMN ping_failed INTEGER NOT NULL DEFAULT 0;";
+
+/// Schema changes in order, user_version of the database is the number of migrations
+/// already applied.
+const MIGRATIONS: &'static [&'static str] = &[CREATE_RESULTS_TABLE, ADD_SOURCE_COLUMN,
+                                                 ADD_IP_VERSION_COLUMN, ADD_LOADED_LATENCY_COLUMNS,
+                                                 ADD_PING_METHOD_COLUMNS, ADD_SKIPPED_COLUMNS,
+                                                 ADD_PING_FAILED_COLUMN];
+
+const SELECT_RESULTS: &'static str = "
+    SELECT test_number, tested_at, client_ip, client_isp, server_id, server_name,
+           server_country, server_url, latency_millis,
+           rx_start, rx_total_bytes, rx_total_millis, rx_speed_mbps, rx_end,
+           tx_start, tx_total_bytes, tx_total_millis, tx_speed_mbps, tx_end, source, ip_version,
+           rx_loaded_latency_millis, tx_loaded_latency_millis, bufferbloat_grade,
+           http_latency_millis, tcp_latency_millis, rx_skipped, tx_skipped, ping_failed
+    FROM results";
+
+
+/// Filters for `stest history`, dates are inclusive and given as YYYY-MM-DD.
+/// Server matches either the numeric server id or the server name.
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    pub from_date: Option<String>,
+    pub to_date: Option<String>,
+    pub server: Option<String>,
+    pub limit: Option<u64>
+}
+
+
+#[derive(Debug, Default)]
+pub struct Percentiles {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64
+}
+
+
+impl Percentiles {
+    pub fn from_values(values: &[f64]) -> Percentiles {
+        Percentiles {
+            mean: mean(values),
+            p50: percentile(values, 50.0),
+            p90: percentile(values, 90.0),
+            p95: percentile(values, 95.0)
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct DailyAverage {
+    pub date: String,
+    pub num_tests: u64,
+    pub latency_millis: f64,
+    pub rx_speed_mbps: f64,
+    pub tx_speed_mbps: f64
+}
+
+
+#[derive(Debug, Default)]
+pub struct HistorySummary {
+    pub num_tests: u64,
+    pub latency_millis: Percentiles,
+    pub rx_speed_mbps: Percentiles,
+    pub tx_speed_mbps: Percentiles,
+    pub daily: Vec<DailyAverage>
+}
+
+
+pub struct HistoryStore {
+    conn: Connection
+}
+
+
+impl HistoryStore {
+    pub fn open(path: &str) -> Result<HistoryStore> {
+        let conn = Connection::open(path)?;
+        HistoryStore::init(conn)
+    }
+
+    pub fn open_in_memory() -> Result<HistoryStore> {
+        let conn = Connection::open_in_memory()?;
+        HistoryStore::init(conn)
+    }
+
+    /// Every migration is applied together with its version bump or not at all, so a
+    /// migration that fails halfway is run again from the start next time.
+    fn init(mut conn: Connection) -> Result<HistoryStore> {
+        let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+        for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.execute_batch(&format!("PRAGMA user_version = {}", idx + 1))?;
+            tx.commit()?;
+        }
+        Ok(HistoryStore { conn: conn })
+    }
+
+    /// Stores a single test, `run_started_at` groups all the tests of one `stest` run.
+    pub fn record(&self, run_started_at: &str, result: &TestResult) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO results (
+                run_started_at, tested_at, test_number, client_ip, client_isp, server_id,
+                server_name, server_country, server_url, latency_millis,
+                rx_start, rx_total_bytes, rx_total_millis, rx_speed_mbps, rx_end,
+                tx_start, tx_total_bytes, tx_total_millis, tx_speed_mbps, tx_end, source,
+                ip_version, rx_loaded_latency_millis, tx_loaded_latency_millis,
+                bufferbloat_grade, http_latency_millis, tcp_latency_millis, rx_skipped,
+                tx_skipped, ping_failed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                     ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30)",
+            &[&run_started_at as &ToSql,
+              &result.tested_at,
+              &(result.test_number as i64),
+              &result.client_ip,
+              &result.client_isp,
+              &(result.server_id as i64),
+              &result.server_name,
+              &result.server_country,
+              &result.server_url,
+              &(result.latency_millis as i64),
+              &result.rx_start,
+              &(result.rx_total_bytes as i64),
+              &(result.rx_total_millis as i64),
+              &result.rx_speed_mbps,
+              &result.rx_end,
+              &result.tx_start,
+              &(result.tx_total_bytes as i64),
+              &(result.tx_total_millis as i64),
+              &result.tx_speed_mbps,
+              &result.tx_end,
+              &result.source,
+              &result.ip_version,
+              &(result.rx_loaded_latency_millis as i64),
+              &(result.tx_loaded_latency_millis as i64),
+              &result.bufferbloat_grade,
+              &(result.http_latency_millis as i64),
+              &(result.tcp_latency_millis as i64),
+              &result.rx_skipped,
+              &result.tx_skipped,
+              &result.ping_failed])?;
+        Ok(())
+    }
+
+    /// Results matching filter, oldest first
+    pub fn query(&self, filter: &HistoryFilter) -> Result<Vec<TestResult>> {
+        let (where_clause, params) = build_where_clause(filter);
+        let mut sql = format!("{}{} ORDER BY tested_at, id", SELECT_RESULTS, where_clause);
+        if let Some(limit) = filter.limit {
+            // Keep the latest `limit` tests but still list them oldest first
+            sql = format!("SELECT * FROM ({}{} ORDER BY tested_at DESC, id DESC LIMIT {}) \
+                           ORDER BY tested_at", SELECT_RESULTS, where_clause, limit);
+        }
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(&params, row_to_test_result)?;
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+        Ok(results)
+    }
+
+    pub fn summarise(&self, filter: &HistoryFilter) -> Result<HistorySummary> {
+        let results = self.query(filter)?;
+        if results.is_empty() {
+            return Ok(HistorySummary::default());
+        }
+
+        // Failed pings have no latency and latency-only tests would drag the speeds down
+        let latencies: Vec<f64> = results.iter().filter(|r| !r.ping_failed).map(|r| r.latency_millis as f64).collect();
+        let rx_speeds: Vec<f64> = results.iter().filter(|r| !r.rx_skipped).map(|r| r.rx_speed_mbps).collect();
+        let tx_speeds: Vec<f64> = results.iter().filter(|r| !r.tx_skipped).map(|r| r.tx_speed_mbps).collect();
+
+        // tested_at is stored as "YYYY-MM-DD HH:MM:SS" so date is the first 10 characters
+        let mut days: BTreeMap<String, Vec<&TestResult>> = BTreeMap::new();
+        for r in &results {
+            let date: String = r.tested_at.chars().take(10).collect();
+            days.entry(date).or_insert(Vec::new()).push(r);
+        }
+
+        let daily = days.into_iter().map(|(date, day_results)| {
+            let day_latencies: Vec<f64> = day_results.iter().filter(|r| !r.ping_failed).map(|r| r.latency_millis as f64).collect();
+            let day_rx_speeds: Vec<f64> = day_results.iter().filter(|r| !r.rx_skipped).map(|r| r.rx_speed_mbps).collect();
+            let day_tx_speeds: Vec<f64> = day_results.iter().filter(|r| !r.tx_skipped).map(|r| r.tx_speed_mbps).collect();
+            DailyAverage {
+                date: date,
+                num_tests: day_results.len() as u64,
+                latency_millis: mean(&day_latencies),
+                rx_speed_mbps: mean(&day_rx_speeds),
+                tx_speed_mbps: mean(&day_tx_speeds)
+            }
+        }).collect();
+
+        Ok(HistorySummary {
+            num_tests: results.len() as u64,
+            latency_millis: Percentiles::from_values(&latencies),
+            rx_speed_mbps: Percentiles::from_values(&rx_speeds),
+            tx_speed_mbps: Percentiles::from_values(&tx_speeds),
+            daily: daily
+        })
+    }
+}
+
+
+/// Default location of the history database - ~/.stest/history.db, falls back to
+/// current directory if home directory cannot be found.
+pub fn default_db_path() -> String {
+    let home = env::var("HOME").or(env::var("USERPROFILE"));
+    match home {
+        Ok(h) => {
+            let mut dir = PathBuf::from(h);
+            dir.push(HISTORY_DIR_NAME);
+            fs::create_dir_all(&dir).ok();
+            dir.push(HISTORY_FILE_NAME);
+            dir.to_string_lossy().into_owned()
+        },
+        Err(_) => HISTORY_FILE_NAME.to_string()
+    }
+}
+
+
+fn build_where_clause(filter: &HistoryFilter) -> (String, Vec<String>) {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<String> = Vec::new();
+
+    if let Some(ref from_date) = filter.from_date {
+        params.push(from_date.clone());
+        conditions.push(format!("date(tested_at) >= date(?{})", params.len()));
+    }
+
+    if let Some(ref to_date) = filter.to_date {
+        params.push(to_date.clone());
+        conditions.push(format!("date(tested_at) <= date(?{})", params.len()));
+    }
+
+    if let Some(ref server) = filter.server {
+        params.push(server.clone());
+        conditions.push(format!("(CAST(server_id AS TEXT) = ?{0} OR server_name LIKE ?{0})",
+                                params.len()));
+    }
+
+    if conditions.is_empty() {
+        (String::new(), params)
+    } else {
+        (format!(" WHERE {}", conditions.join(" AND ")), params)
+    }
+}
+
+
+fn row_to_test_result(row: &Row) -> Result<TestResult> {
+    Ok(TestResult {
+        test_number: row.get::<_, i64>(0)? as u64,
+        tested_at: row.get(1)?,
+        client_ip: row.get(2)?,
+        client_isp: row.get(3)?,
+        server_id: row.get::<_, i64>(4)? as u64,
+        server_name: row.get(5)?,
+        server_country: row.get(6)?,
+        server_url: row.get(7)?,
+        latency_millis: row.get::<_, i64>(8)? as u64,
+        rx_start: row.get(9)?,
+        rx_total_bytes: row.get::<_, i64>(10)? as u64,
+        rx_total_millis: row.get::<_, i64>(11)? as u64,
+        rx_speed_mbps: row.get(12)?,
+        rx_end: row.get(13)?,
+        tx_start: row.get(14)?,
+        tx_total_bytes: row.get::<_, i64>(15)? as u64,
+        tx_total_millis: row.get::<_, i64>(16)? as u64,
+        tx_speed_mbps: row.get(17)?,
+        tx_end: row.get(18)?,
+        source: row.get(19)?,
+        ip_version: row.get(20)?,
+        rx_loaded_latency_millis: row.get::<_, i64>(21)? as u64,
+        tx_loaded_latency_millis: row.get::<_, i64>(22)? as u64,
+        bufferbloat_grade: row.get(23)?,
+        http_latency_millis: row.get::<_, i64>(24)? as u64,
+        tcp_latency_millis: row.get::<_, i64>(25)? as u64,
+        rx_skipped: row.get(26)?,
+        tx_skipped: row.get(27)?,
+        ping_failed: row.get(28)?
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryStore, HistoryFilter, CREATE_RESULTS_TABLE, ADD_SOURCE_COLUMN, ADD_IP_VERSION_COLUMN};
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::env;
+    use std::fs;
+    use results::TestResult;
+
+    fn test_result(tested_at: &str, server_id: u64, rx: f64, tx: f64, latency: u64) -> TestResult {
+        TestResult {
+            test_number: 1,
+            tested_at: tested_at.to_string(),
+            server_id: server_id,
+            server_name: format!("Server {}", server_id),
+            latency_millis: latency,
+            rx_speed_mbps: rx,
+            tx_speed_mbps: tx,
+            ..TestResult::default()
+        }
+    }
+
+    fn populated_store() -> HistoryStore {
+        let store = HistoryStore::open_in_memory().unwrap();
+        store.record("2017-03-01 10:00:00", &test_result("2017-03-01 10:00:00", 1, 10.0, 1.0, 20)).unwrap();
+        store.record("2017-03-01 10:00:00", &test_result("2017-03-01 10:01:00", 1, 20.0, 2.0, 30)).unwrap();
+        store.record("2017-03-02 10:00:00", &test_result("2017-03-02 10:00:00", 2, 30.0, 3.0, 40)).unwrap();
+        store
+    }
+
+    #[test]
+    fn query_all_test() {
+        let store = populated_store();
+        let results = store.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(3, results.len());
+        assert_eq!("2017-03-01 10:00:00", results[0].tested_at);
+        assert_eq!(30.0, results[2].rx_speed_mbps);
+    }
+
+    #[test]
+    fn query_by_date_and_server_test() {
+        let store = populated_store();
+        let by_date = HistoryFilter { from_date: Some("2017-03-02".to_string()), ..HistoryFilter::default() };
+        assert_eq!(1, store.query(&by_date).unwrap().len());
+
+        let by_server = HistoryFilter { server: Some("1".to_string()), ..HistoryFilter::default() };
+        assert_eq!(2, store.query(&by_server).unwrap().len());
+
+        let by_name = HistoryFilter { server: Some("Server 2".to_string()), ..HistoryFilter::default() };
+        assert_eq!(1, store.query(&by_name).unwrap().len());
+    }
+
+    #[test]
+    fn query_limit_keeps_latest_test() {
+        let store = populated_store();
+        let filter = HistoryFilter { limit: Some(2), ..HistoryFilter::default() };
+        let results = store.query(&filter).unwrap();
+        assert_eq!(2, results.len());
+        assert_eq!("2017-03-01 10:01:00", results[0].tested_at);
+    }
+
+    #[test]
+    fn source_is_stored_test() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let mut result = test_result("2017-03-01 10:00:00", 1, 10.0, 1.0, 20);
+        result.source = "wwan0".to_string();
+        store.record("2017-03-01 10:00:00", &result).unwrap();
+        assert_eq!("wwan0", store.query(&HistoryFilter::default()).unwrap()[0].source);
+    }
+
+    #[test]
+    fn migrates_existing_database_test() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(CREATE_RESULTS_TABLE).unwrap();
+        let store = HistoryStore::init(conn).unwrap();
+        store.record("2017-03-01 10:00:00", &test_result("2017-03-01 10:00:00", 1, 10.0, 1.0, 20)).unwrap();
+        assert_eq!("", store.query(&HistoryFilter::default()).unwrap()[0].source);
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back_test() {
+        let mut path = env::temp_dir();
+        path.push(format!("stest-history-{}.db", ::rand::random::<u32>()));
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(&format!("{}{}{} PRAGMA user_version = 3;", CREATE_RESULTS_TABLE,
+                                        ADD_SOURCE_COLUMN, ADD_IP_VERSION_COLUMN)).unwrap();
+            // Second column of the loaded latency migration clashes, so it fails after
+            // adding its first column
+            conn.execute_batch("ALTER TABLE results ADD COLUMN tx_loaded_latency_millis INTEGER").unwrap();
+        }
+        assert!(HistoryStore::open(&path.to_string_lossy()).is_err());
+
+        let conn = Connection::open(&path).unwrap();
+        let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(3, version);
+        assert!(conn.prepare("SELECT rx_loaded_latency_millis FROM results").is_err());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn summarise_test() {
+        let store = populated_store();
+        let summary = store.summarise(&HistoryFilter::default()).unwrap();
+        assert_eq!(3, summary.num_tests);
+        assert_eq!(20.0, summary.rx_speed_mbps.p50);
+        assert_eq!(30.0, summary.latency_millis.mean);
+        assert_eq!(2, summary.daily.len());
+        assert_eq!("2017-03-01", summary.daily[0].date);
+        assert_eq!(15.0, summary.daily[0].rx_speed_mbps);
+    }
+
+    #[test]
+    fn summarise_skips_latency_only_tests_test() {
+        let store = populated_store();
+        let mut ping_only = test_result("2017-03-02 11:00:00", 2, 0.0, 0.0, 10);
+        ping_only.rx_skipped = true;
+        ping_only.tx_skipped = true;
+        store.record("2017-03-02 11:00:00", &ping_only).unwrap();
+
+        let summary = store.summarise(&HistoryFilter::default()).unwrap();
+        assert_eq!(4, summary.num_tests);
+        assert_eq!(20.0, summary.rx_speed_mbps.mean);
+        assert_eq!(25.0, summary.latency_millis.mean);
+        assert_eq!(30.0, summary.daily[1].rx_speed_mbps);
+        assert!(store.query(&HistoryFilter::default()).unwrap()[3].tx_skipped);
+    }
+
+    #[test]
+    fn summarise_skips_failed_pings_test() {
+        let store = populated_store();
+        let mut unanswered = test_result("2017-03-02 11:00:00", 2, 30.0, 3.0, 0);
+        unanswered.ping_failed = true;
+        store.record("2017-03-02 11:00:00", &unanswered).unwrap();
+
+        let summary = store.summarise(&HistoryFilter::default()).unwrap();
+        assert_eq!(30.0, summary.latency_millis.mean);
+        assert!(store.query(&HistoryFilter::default()).unwrap()[3].ping_failed);
+    }
+}
@@ -7,43 +7,82 @@ extern crate csv;
 extern crate clap;
 extern crate chrono;
 extern crate rand;
-extern crate hyper_timeout_connector;
+extern crate rusqlite;
+extern crate rustc_serialize;
+extern crate toml;
+extern crate hyper_native_tls;
+extern crate socket2;
+extern crate websocket;
+#[macro_use]
+extern crate log;
 
+#[macro_use]
+pub mod output;
+pub mod logging;
 pub mod file_utils;
 pub mod geo;
 pub mod upload_data;
 pub mod config;
 pub mod utils;
 pub mod time_utils;
+pub mod stats;
+pub mod units;
+pub mod results;
+pub mod history;
+pub mod budget;
+pub mod nagios;
+pub mod client;
+pub mod connector;
+pub mod pool;
+pub mod worker;
+pub mod bufferbloat;
+pub mod ookla;
+pub mod settings;
+pub mod runner;
+pub mod daemon;
+pub mod progress;
+pub mod tui;
 
 use std::io::Read;
 use std::io::Write;
 use std::collections::{HashMap, BTreeMap};
-use std::thread;
 use std::time;
 use std::time::Instant;
 //use std::io::prelude::*;
 use std::io;
 use std::fs::File;
 
-use hyper::client::Client;
 use hyper::client::response::Response;
-use hyper::client::RedirectPolicy;
 use hyper::client::Body;
-use hyper::header::{Headers, UserAgent, Header, ContentLength};
+use hyper::header::{Header, ContentLength};
 use xml::reader::{EventReader, XmlEvent};
 use xml::attribute::OwnedAttribute;
 use url::{Url, Host};
-use hyper_timeout_connector::HttpTimeoutConnector;
 
 use config::TestServerConfig;
+use client::{ClientOptions, PingMethod, new_pooled_client, default_headers};
+use pool::{ConnectionPool, drain};
 use utils::compute_speed_in_mbps;
+use ookla::Transport;
+use progress::Phase;
 
 
+/// Number of closest servers latency is measured for when picking the best one
+pub const CLOSEST_SERVERS: usize = 5;
+
 pub fn pick_closest_servers(client_location: (f32, f32),
                         all_test_servers: &Vec<TestServerConfig>,
                         result: &mut Vec<TestServerConfig>)
                         -> () {
+    pick_n_closest_servers(client_location, all_test_servers, CLOSEST_SERVERS, result)
+}
+
+
+pub fn pick_n_closest_servers(client_location: (f32, f32),
+                              all_test_servers: &Vec<TestServerConfig>,
+                              max_servers: usize,
+                              result: &mut Vec<TestServerConfig>)
+                              -> () {
     // NB: It is important to maintain resul type to be Vec<TestServerConfig> as if
     //     user switches this picking closest servers off, you can still just pass
     //     on servers without having to manipulate types!
@@ -57,11 +96,10 @@ pub fn pick_closest_servers(client_location: (f32, f32),
         let client_lon = client_location.1;
         let dist = geo::calc_distance_in_km((client_lat, client_lon),
                                             (server.latitude, server.longitude));
-//        println!("distance {}", dist);
+        trace!("Server {} ({}) is {:.0}km away", server.name, server.id, dist);
         distance_map.insert(dist.round() as u64, server);
     }
 
-    let max_servers = 5;
     let mut count = 0;
 
     for (_, v) in distance_map.iter() {
@@ -86,178 +124,255 @@ pub fn pick_closest_servers(client_location: (f32, f32),
 
 type Latency = u64;
 
-pub fn find_best_server_by_ping(test_servers: &Vec<TestServerConfig>)
-                            -> (&TestServerConfig, Latency) {
-    let mut server_responses: BTreeMap<u64, &TestServerConfig> = BTreeMap::new();
-
-    for s in test_servers {
-        let server_url = Url::parse(s.url.as_str()).unwrap();
-        let server_url_str = server_url.host_str().unwrap();
-//        println!("{}", server_url_str);
-        let latency_url = format!("http://{}/speedtest/latency.txt", server_url_str);
-        let latency_url_str = latency_url.as_str();
-//        println!("{}", latency_url_str);
-
-        let mut total: u64 = 0;
-
-        for i in 0..3 {
-            let start = Instant::now();
-            let mut connector = HttpTimeoutConnector::new();
-            connector.set_connect_timeout(Some(time::Duration::from_secs(30)));
-
-            let mut client = Client::with_connector(connector);
-            client.set_read_timeout(Some(time::Duration::from_secs(10)));
-            client.set_write_timeout(Some(time::Duration::from_secs(10)));
-
-            client.set_redirect_policy(RedirectPolicy::FollowAll);
-            let mut headers = Headers::new();
-            headers.set(UserAgent("Hyper-speedtest".to_owned()));
-            let mut response = client.get(latency_url_str)
-                .headers(headers)
-                .send();
+const DEFAULT_SERVER_PORT: u16 = 8080;
 
-            match response {
-                Ok(resp)    => {
-//                    println!("{:?}", resp);
+pub fn find_best_server_by_ping<'a>(test_servers: &'a Vec<TestServerConfig>, options: &ClientOptions)
+                            -> (&'a TestServerConfig, Option<Latency>) {
+    let (best_server, latency) = rank_servers_by_ping(test_servers, options)[0];
+    chatter!("The chosen server is {:?} with {} 'ping' latency {}", best_server.name,
+             options.ping_method.label().to_uppercase(), describe_latency(latency));
+    (best_server, latency)
+}
 
-                    if resp.status == hyper::Ok {
-                        let elapsed = start.elapsed();
-                        let elapsed_as_millis = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
-//                        println!("Time taken span {:?}", elapsed_as_millis);
-                        total = total + elapsed_as_millis;
 
-                    } else {
-                        // Assuming this server isn't too good - so weighing out
-                        total = total + 360000 as u64;
-                    }
+/// Every server with its latency, lowest latency first and servers that did not answer
+/// any ping last
+pub fn rank_servers_by_ping<'a>(test_servers: &'a [TestServerConfig], options: &ClientOptions)
+                                -> Vec<(&'a TestServerConfig, Option<Latency>)> {
+    let mut ranked: Vec<(&TestServerConfig, Option<Latency>)> = test_servers.iter()
+        .map(|s| (s, measure_latency(s, options)))
+        .collect();
+    ranked.sort_by_key(|&(_, latency)| latency.unwrap_or(Latency::max_value()));
+    ranked
+}
 
-                },
-                Err(e)      => {
-//                    println!("Failed to get response on ping");
-                    // Failure responses are weighed 360000 = 1hr in millis
-                    total = total + 3600000 as u64;
+
+/// "21ms", or "failed" when the server did not answer any ping
+pub fn describe_latency(latency: Option<Latency>) -> String {
+    match latency {
+        Some(l) => format!("{}ms", l),
+        None    => "failed".to_string()
+    }
+}
+
+
+/// latency.txt on the host of given server
+pub fn latency_url(server: &TestServerConfig, options: &ClientOptions) -> String {
+    let server_url = Url::parse(server.url.as_str()).unwrap();
+    let server_url_str = server_url.host_str().unwrap();
+    format!("{}://{}/speedtest/latency.txt", options.scheme(), server_url_str)
+}
+
+
+/// Latency to given server using the ping method of options, PING command of the Ookla
+/// protocol when that is the transport. None when the server did not answer any ping.
+pub fn measure_latency(server: &TestServerConfig, options: &ClientOptions) -> Option<Latency> {
+    if options.transport == Transport::Ookla {
+        return ookla::measure_latency(server, options);
+    }
+
+    match options.ping_method {
+        PingMethod::Http    => measure_http_latency(server, options),
+        PingMethod::Tcp     => measure_tcp_latency(server, options)
+    }
+}
+
+
+/// Average TCP handshake time in millis to host:port of given server over the successful
+/// ones of 3 connects, None when all of them failed. No HTTP is involved (and proxy is
+/// not used) so this is close to network round trip time.
+pub fn measure_tcp_latency(server: &TestServerConfig, options: &ClientOptions) -> Option<Latency> {
+    let (host, port) = parse_host_port(&server.host);
+    let connector = options.connector();
+    let mut answered: Vec<Latency> = Vec::new();
+
+    for _ in 0..3 {
+        let start = Instant::now();
+        match connector.connect_tcp(&host, port) {
+            Ok(_)   => {
+                let elapsed = start.elapsed();
+                answered.push((elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64);
+            },
+            Err(e)  => debug!("TCP connect to {}:{} failed - {}", host, port, e)
+        }
+    }
+
+    average_latency(&answered)
+}
+
+
+/// Average time taken in millis to fetch latency.txt from given server over the successful
+/// ones of 3 requests, None when none of them succeeded.
+pub fn measure_http_latency(server: &TestServerConfig, options: &ClientOptions) -> Option<Latency> {
+    let latency_url = latency_url(server, options);
+    let latency_url_str = latency_url.as_str();
+
+    let mut answered: Vec<Latency> = Vec::new();
+    // Single kept alive connection, so only the first request pays for the handshake
+    let client = new_pooled_client(options, latency_url_str, options.network.io_timeout, 1);
+    let headers = default_headers(options);
+
+    for _ in 0..3 {
+        let start = Instant::now();
+        let response = client.get(latency_url_str)
+            .headers(headers.clone())
+            .send();
+
+        match response {
+            Ok(mut resp)    => {
+                drain(&mut resp).ok();
+
+                if resp.status == hyper::Ok {
+                    let elapsed = start.elapsed();
+                    let elapsed_as_millis = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
+                    debug!("{} answered in {}ms", latency_url_str, elapsed_as_millis);
+                    answered.push(elapsed_as_millis);
+
+                } else {
+                    debug!("{} returned {}", latency_url_str, resp.status);
+                }
+
+            },
+            Err(e)      => {
+                if logging::is_hyper_timeout(&e) {
+                    debug!("{} timed out", latency_url_str);
+                } else {
+                    debug!("{} failed - {}", latency_url_str, e);
                 }
             }
         }
-
-        let latency_avg = total / 3;
-        server_responses.insert(latency_avg, s);
     }
 
-    let (latency, best_server) = server_responses.iter().next().unwrap();
-    println!("The chosen server is {:?} with HTTP 'ping' latency {:?}ms", best_server.name, latency);
-    (best_server, *latency)
+    average_latency(&answered)
+}
+
+
+/// Mean of answered pings, None when there are none
+pub fn average_latency(answered: &[Latency]) -> Option<Latency> {
+    if answered.is_empty() {
+        None
+    } else {
+        Some(answered.iter().sum::<Latency>() / answered.len() as Latency)
+    }
 }
 
 
-pub fn perform_download_test(server_url_str: &str, dimensions: &Vec<u64>) -> (u64, u64, f64) {
+pub fn perform_download_test(server_url_str: &str, dimensions: &Vec<u64>,
+                             options: &ClientOptions) -> (u64, u64, f64) {
     let mut urls: Vec<String> = Vec::new();
     let mut counter = 0;
 
     for dim in dimensions {
         // 4 threads per URL
         for _ in 0..4 {
-            let url = format!("http://{}/speedtest/random{}x{}.jpg?x={}.{}", server_url_str,
+            let url = format!("{}://{}/speedtest/random{}x{}.jpg?x={}.{}", options.scheme(), server_url_str,
                               dim, dim, ext_time::precise_time_s(), counter);
             counter = counter + 1;
             urls.push(url);
         }
     }
 
-//    println!("{:?}", urls.len());
-    let mut thread_handles = vec![];
+    debug!("Downloading {} images", urls.len());
+    if urls.is_empty() {
+        return (0, 0, 0.0);
+    }
+    let pool = ConnectionPool::new(options, &urls[0], options.network.io_timeout);
+    let download_cutoff = options.network.download_cutoff;
+    let network = options.network.clone();
+    let meter = options.progress.meter(Phase::Download);
+    let counter = meter.counter();
     let start = time::Instant::now();
 
-    for url in urls {
-        let handle = thread::spawn(move || {
-            let mut connector = HttpTimeoutConnector::new();
-            connector.set_connect_timeout(Some(time::Duration::from_secs(30)));
-
-            let mut client = Client::with_connector(connector);
-            client.set_read_timeout(Some(time::Duration::from_secs(10)));
-            client.set_write_timeout(Some(time::Duration::from_secs(10)));
+    let downloaded = pool.run(urls, move |client, headers, url| {
+        let elapsed = start.elapsed();
+        if elapsed >= download_cutoff || network.reached_max_bytes(counter.total()) {
+            // Link is slow or enough data was used - so not worth requesting any more images
+            return 0 as u64;
+        }
 
-            client.set_redirect_policy(RedirectPolicy::FollowAll);
+        let response = client.get(url.as_str())
+            .headers(headers.clone())
+            .send();
 
-            let mut headers = Headers::new();
-            headers.set(UserAgent("Hyper-speedtest".to_owned()));
-            let mut response = client.get(url.as_str())
-                .headers(headers)
-                .send();
+        match response {
+            Ok(mut res)   => {
+                if res.status == hyper::Ok {
+                    let mut all_read = false;
+                    let mut read_bytes = 0;
+                    let mut buf: Vec<u8> = vec![0; 8192];
 
-            match response {
-                Ok(mut res)   => {
-                    if res.status == hyper::Ok {
-                        let mut all_read = false;
-                        let mut read_bytes = 0;
-
-                        while !all_read {
-                            let elapsed = start.elapsed();
-                            if elapsed.as_secs() >= 10 {
-                                // Link is slow - so not worth reading any more and quit this thread
-                                break;
-                            }
+                    while !all_read {
+                        let elapsed = start.elapsed();
+                        let wanted = counter.reserve(buf.len() as u64, network.max_bytes);
+                        if elapsed >= download_cutoff || wanted == 0 {
+                            // Link is slow or enough data was used - so not worth reading
+                            // any more, connection is dropped as the body is not read to the end
+                            counter.remove(wanted);
+                            break;
+                        }
 
-                            let mut buf: Vec<u8> = vec![0; 8192];
-                            let size = res.read(&mut buf);
-                            match size {
-                                Ok(s)   => {
-                                    read_bytes = read_bytes + s as u64;
-                                    if s == 0  {
-                                        // break out of loop as all read!
-                                        all_read = true;
-                                    }
-                                },
-                                Err(e) => {
+                        let size = res.read(&mut buf[..wanted as usize]);
+                        counter.remove(wanted - size.as_ref().map(|&s| s as u64).unwrap_or(0));
+                        match size {
+                            Ok(s)   => {
+                                read_bytes = read_bytes + s as u64;
+                                if s == 0  {
+                                    // break out of loop as all read!
                                     all_read = true;
                                 }
+                            },
+                            Err(e) => {
+                                if logging::is_timeout(&e) {
+                                    warn!("Timed out reading {} after {} bytes", url, read_bytes);
+                                } else {
+                                    warn!("Failed reading {} after {} bytes - {}", url, read_bytes, e);
+                                }
+                                all_read = true;
                             }
-
                         }
-//                        println!("Downloaded = {} in {} seconds", read_bytes, start.elapsed().as_secs());
-                        print!(".");
-                        io::stdout().flush().ok().expect("");
-//                        io::stdout().write_all("\x1b[1K".as_bytes()).unwrap();
-                        read_bytes
 
-                    } else {
-                        0 as u64
                     }
-                }
-                Err(res)    => {
+                    debug!("Downloaded {} bytes of {}", read_bytes, url);
+                    chatter_inline!(".");
+//                        io::stdout().write_all("\x1b[1K".as_bytes()).unwrap();
+                    read_bytes
+
+                } else {
+                    warn!("{} returned {}", url, res.status);
+                    drain(&mut res).ok();
                     0 as u64
                 }
             }
+            Err(e)      => {
+                if logging::is_hyper_timeout(&e) {
+                    warn!("{} timed out", url);
+                } else {
+                    warn!("{} failed - {}", url, e);
+                }
+                0 as u64
+            }
+        }
+    });
 
-        });
-        thread_handles.push(handle);
-    }
-
-    let mut total_download_bytes = 0;
-
-    for h in thread_handles {
-        let file_size = h.join();
-        total_download_bytes = total_download_bytes + file_size.unwrap_or(0);
-    }
-    print!("Done\n");
-    io::stdout().flush().ok().expect("");
+    let total_download_bytes: u64 = downloaded.iter().sum();
+    meter.stop();
+    chatter_inline!("Done\n");
 
     let elapsed = start.elapsed();
     let elapsed_as_millis = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
-    println!("Downloaded {} bytes in {}ms",
+    chatter!("Downloaded {} bytes in {}ms",
              total_download_bytes,
              elapsed_as_millis);
     let speed_in_mbps = compute_speed_in_mbps(total_download_bytes, elapsed_as_millis);
-    println!("Download speed: {} Mbps", speed_in_mbps);
     (total_download_bytes, elapsed_as_millis, speed_in_mbps)
 }
 
 
 pub fn perform_upload_test(server_url_str: &str,
                        client_conf: &config::UploadConfig,
-                       sizes: &Vec<u64>) -> (u64, u64, f64) {
+                       sizes: &Vec<u64>,
+                       options: &ClientOptions) -> (u64, u64, f64) {
     io::stdout().flush().ok().expect("");
-    let mut thread_handles = vec![];
     let start = time::Instant::now();
     let ratio = client_conf.ratio;
     let max_chunk_count = client_conf.maxchunkcount;
@@ -283,66 +398,140 @@ pub fn perform_upload_test(server_url_str: &str,
 
     let picked_sizes = all_sizes.into_iter().take(max_chunk_count as usize);
 
-    for s in picked_sizes {
-        let full_size = s.clone();
-        let upload_url = server_url_str.to_string();
-
-        let handle = thread::spawn(move || {
-            let mut total_bytes_uploaded = 0;
-
-            let mut connector = HttpTimeoutConnector::new();
-            connector.set_connect_timeout(Some(time::Duration::from_secs(30)));
-
-            let mut client = Client::with_connector(connector);
-            client.set_redirect_policy(RedirectPolicy::FollowAll);
-            client.set_read_timeout(Some(time::Duration::from_secs(5)));
-            client.set_write_timeout(Some(time::Duration::from_secs(5)));
-
-            let mut headers = Headers::new();
-            headers.set(UserAgent("Hyper-speedtest".to_owned()));
-
-            let mut buffered = upload_data::UploadData::new(full_size, upload_length);
-            {
-                let mut response = client.post(upload_url.as_str())
-                    //.body(Body::BufBody(&buff, full_size as usize))
-                    .body(Body::ChunkedBody(&mut buffered))
-                    .headers(headers.clone())
-                    .send();
-
-                match response {
-                    Ok(res)     => {
-                        //                    println!("{:?}", res);
-                        print!(".");
-                        io::stdout().flush().ok().expect("");
-                    },
-                    Err(e)       => {}
-                }
-            }
-            total_bytes_uploaded = total_bytes_uploaded + buffered.current_size;
-            total_bytes_uploaded
+    let upload_url = options.with_scheme(server_url_str);
+    let pool = ConnectionPool::new(options, &upload_url, options.network.upload_io_timeout);
+    let max_bytes = options.network.max_bytes;
+    let meter = options.progress.meter(Phase::Upload);
+    let counter = meter.counter();
 
-        });
+    let uploaded = pool.run(picked_sizes.collect(), move |client, headers, full_size| {
+        if max_bytes.map_or(false, |max| counter.total() >= max) {
+            return 0;
+        }
+        let mut buffered = upload_data::UploadData::new(full_size, upload_length)
+            .with_counter(counter.clone(), max_bytes);
+        {
+            let response = client.post(upload_url.as_str())
+                //.body(Body::BufBody(&buff, full_size as usize))
+                .body(Body::ChunkedBody(&mut buffered))
+                .headers(headers.clone())
+                .send();
 
-        thread_handles.push(handle);
-    }
+            match response {
+                Ok(mut res)     => {
+                    if res.status == hyper::Ok {
+                        debug!("Uploaded {} bytes", buffered.current_size);
+                    } else {
+                        warn!("{} returned {} after {} bytes", upload_url, res.status, buffered.current_size);
+                    }
+                    drain(&mut res).ok();
+                    chatter_inline!(".");
+                },
+                Err(e)          => {
+                    if logging::is_hyper_timeout(&e) {
+                        warn!("{} timed out after {} bytes", upload_url, buffered.current_size);
+                    } else {
+                        warn!("{} failed after {} bytes - {}", upload_url, buffered.current_size, e);
+                    }
+                }
+            }
+        }
+        buffered.current_size
+    });
 
-    let mut total_upload_bytes = 0;
-    for h in thread_handles {
-        let uploaded = h.join();
-        total_upload_bytes = total_upload_bytes + uploaded.unwrap_or(0);
-    }
-    print!("Done\n");
-    io::stdout().flush().ok().expect("");
+    let total_upload_bytes: u64 = uploaded.iter().sum();
+    meter.stop();
+    chatter_inline!("Done\n");
     let elapsed = start.elapsed();
     let elapsed_as_millis = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
-    println!("Uploaded {} bytes in {}ms", total_upload_bytes, elapsed_as_millis);
+    chatter!("Uploaded {} bytes in {}ms", total_upload_bytes, elapsed_as_millis);
     let speed_in_mbps = compute_speed_in_mbps(total_upload_bytes, elapsed_as_millis);
-    println!("Upload speed: {} Mbps", speed_in_mbps);
     (total_upload_bytes, elapsed_as_millis, speed_in_mbps)
 
 }
 
+/// Splits host entry of server list ("host:8080") into host and port, port defaults to
+/// 8080 which all speedtest servers listen on.
+pub fn parse_host_port(host: &str) -> (String, u16) {
+    match host.rfind(':') {
+        // Skip colons of a bare IPv6 address
+        Some(idx) if !host[..idx].contains(':') || host[..idx].ends_with(']') => {
+            let port = host[idx + 1..].parse::<u16>().unwrap_or(DEFAULT_SERVER_PORT);
+            (host[..idx].to_string(), port)
+        },
+        _ => (host.to_string(), DEFAULT_SERVER_PORT)
+    }
+}
+
 pub fn parse_url(server_url: &str) -> String {
     let url_obj = Url::parse(server_url).unwrap();
     url_obj.host_str().unwrap().to_string()
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_host_port, measure_tcp_latency, pick_n_closest_servers, rank_servers_by_ping,
+                perform_download_test};
+    use client::{ClientOptions, PingMethod};
+    use config::TestServerConfig;
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse_host_port_test() {
+        assert_eq!(("speedtest.example.com".to_string(), 8080), parse_host_port("speedtest.example.com:8080"));
+        assert_eq!(("speedtest.example.com".to_string(), 8080), parse_host_port("speedtest.example.com"));
+        assert_eq!(("[::1]".to_string(), 5060), parse_host_port("[::1]:5060"));
+        assert_eq!(("::1".to_string(), 8080), parse_host_port("::1"));
+    }
+
+    #[test]
+    fn tcp_latency_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServerConfig {
+            url: String::new(),
+            latitude: 0.0,
+            longitude: 0.0,
+            name: String::new(),
+            country: String::new(),
+            country_code: String::new(),
+            id: 1,
+            url2: String::new(),
+            host: listener.local_addr().unwrap().to_string()
+        };
+        assert!(measure_tcp_latency(&server, &ClientOptions::default()).unwrap() < 1000);
+    }
+
+    #[test]
+    fn rank_servers_by_ping_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = TestServerConfig { id: 1, host: closed.local_addr().unwrap().to_string(), ..TestServerConfig::default() };
+        drop(closed);
+        let reachable = TestServerConfig { id: 2, host: listener.local_addr().unwrap().to_string(), ..TestServerConfig::default() };
+
+        let options = ClientOptions { ping_method: PingMethod::Tcp, ..ClientOptions::default() };
+        let servers = vec![unreachable, reachable];
+        let ranked = rank_servers_by_ping(&servers, &options);
+        assert_eq!(vec![2, 1], ranked.iter().map(|&(s, _)| s.id).collect::<Vec<u64>>());
+        assert_eq!(None, ranked[1].1);
+    }
+
+    #[test]
+    fn download_without_dimensions_test() {
+        assert_eq!((0, 0, 0.0), perform_download_test("127.0.0.1", &vec![], &ClientOptions::default()));
+    }
+
+    #[test]
+    fn pick_n_closest_servers_test() {
+        let servers: Vec<TestServerConfig> = (1..10).map(|i| TestServerConfig {
+            id: i,
+            latitude: i as f32,
+            longitude: 0.0,
+            ..TestServerConfig::default()
+        }).collect();
+        let mut closest = Vec::new();
+        pick_n_closest_servers((0.0, 0.0), &servers, 3, &mut closest);
+        assert_eq!(vec![1, 2, 3], closest.iter().map(|s| s.id).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use chrono;
+use hyper;
+use log;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rustc_serialize::json::{Json, ToJson};
+
+const CRATE_TARGET_PREFIX: &'static str = "stest";
+/// Lines held back beyond this many are dropped, only counted
+const MAX_HELD_LINES: usize = 1000;
+
+static HOLD: AtomicBool = AtomicBool::new(false);
+
+
+/// How log records are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<LogFormat, String> {
+        match format.to_lowercase().as_str() {
+            "text"  => Ok(LogFormat::Text),
+            "json"  => Ok(LogFormat::Json),
+            _       => Err(format!("Invalid log format '{}', it should be text or json", format))
+        }
+    }
+}
+
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        LogFormat::Text
+    }
+}
+
+
+/// Warnings and errors by default, -v adds info, -vv debug and -vvv trace which also
+/// lets through records of libraries (e.g. hyper). -q leaves only errors.
+pub fn level_filter(verbosity: u64, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbosity {
+        0   => LevelFilter::Warn,
+        1   => LevelFilter::Info,
+        2   => LevelFilter::Debug,
+        _   => LevelFilter::Trace
+    }
+}
+
+
+/// Writes every record as a line on stderr so stdout stays for results. Lines logged
+/// while `hold` is set are kept until it is cleared, up to MAX_HELD_LINES.
+pub struct StderrLogger {
+    level: LevelFilter,
+    format: LogFormat,
+    hold: &'static AtomicBool,
+    held: Mutex<HeldLines>
+}
+
+
+#[derive(Default)]
+struct HeldLines {
+    lines: Vec<String>,
+    dropped: usize
+}
+
+
+impl StderrLogger {
+    pub fn new(level: LevelFilter, format: LogFormat) -> StderrLogger {
+        StderrLogger::with_hold(level, format, &HOLD)
+    }
+
+    fn with_hold(level: LevelFilter, format: LogFormat, hold: &'static AtomicBool) -> StderrLogger {
+        StderrLogger { level: level, format: format, hold: hold, held: Mutex::new(HeldLines::default()) }
+    }
+
+    fn write_held(&self) {
+        if let Ok(mut held) = self.held.lock() {
+            let stderr = io::stderr();
+            let mut handle = stderr.lock();
+            for line in held.lines.drain(..) {
+                writeln!(handle, "{}", line).ok();
+            }
+            if held.dropped > 0 {
+                let timestamp = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%z").to_string();
+                writeln!(handle, "{}", format_record(self.format, &timestamp, Level::Warn, "stest_lib::logging",
+                                                     thread::current().name(),
+                                                     &format!("{} more log lines were dropped", held.dropped))).ok();
+                held.dropped = 0;
+            }
+        }
+    }
+}
+
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level &&
+            (metadata.target().starts_with(CRATE_TARGET_PREFIX) || self.level == LevelFilter::Trace)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%z").to_string();
+        let line = format_record(self.format, &timestamp, record.level(), record.target(),
+                                 thread::current().name(), &record.args().to_string());
+        if self.hold.load(Ordering::SeqCst) {
+            if let Ok(mut held) = self.held.lock() {
+                if held.lines.len() < MAX_HELD_LINES {
+                    held.lines.push(line);
+                } else {
+                    held.dropped += 1;
+                }
+                return;
+            }
+        }
+        let stderr = io::stderr();
+        let mut handle = stderr.lock();
+        writeln!(handle, "{}", line).ok();
+    }
+
+    fn flush(&self) {
+        if !self.hold.load(Ordering::SeqCst) {
+            self.write_held();
+        }
+        io::stderr().flush().ok();
+    }
+}
+
+
+/// Installs the stderr logger, can only be done once per process
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), String> {
+    log::set_boxed_logger(Box::new(StderrLogger::new(level, format)))
+        .map_err(|e| format!("Cannot set up logging - {}", e))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+
+/// Keeps log lines back while e.g. the dashboard owns the terminal, where they would be
+/// drawn over or lost with the alternate screen
+pub fn hold() {
+    HOLD.store(true, Ordering::SeqCst);
+}
+
+
+/// Writes lines kept back since `hold` and logs straight to stderr again
+pub fn release() {
+    HOLD.store(false, Ordering::SeqCst);
+    log::logger().flush();
+}
+
+
+/// Text lines look like "WARN  stest_lib::config: ...", json lines have timestamp,
+/// level, target, thread and message keys.
+fn format_record(format: LogFormat, timestamp: &str, level: Level, target: &str,
+                 thread: Option<&str>, message: &str) -> String {
+    match format {
+        LogFormat::Text => {
+            match thread {
+                Some(t) if t != "main"  => format!("{:<5} {} [{}]: {}", level, target, t, message),
+                _                       => format!("{:<5} {}: {}", level, target, message)
+            }
+        },
+        LogFormat::Json => {
+            let mut d = BTreeMap::new();
+            d.insert("timestamp".to_string(), timestamp.to_json());
+            d.insert("level".to_string(), level.to_string().to_json());
+            d.insert("target".to_string(), target.to_json());
+            d.insert("thread".to_string(), thread.map_or(Json::Null, |t| t.to_json()));
+            d.insert("message".to_string(), message.to_json());
+            Json::Object(d).to_string()
+        }
+    }
+}
+
+
+/// Socket read/write timeouts show up as WouldBlock on unix and TimedOut on windows
+pub fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock
+}
+
+
+pub fn is_hyper_timeout(e: &hyper::Error) -> bool {
+    match *e {
+        hyper::Error::Io(ref io)    => is_timeout(io),
+        _                           => false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{LogFormat, StderrLogger, MAX_HELD_LINES, level_filter, format_record, is_timeout};
+    use log::{Level, LevelFilter, Log, Record};
+    use rustc_serialize::json::Json;
+    use std::io::{Error, ErrorKind};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn level_filter_test() {
+        assert_eq!(LevelFilter::Warn, level_filter(0, false));
+        assert_eq!(LevelFilter::Info, level_filter(1, false));
+        assert_eq!(LevelFilter::Debug, level_filter(2, false));
+        assert_eq!(LevelFilter::Trace, level_filter(5, false));
+        assert_eq!(LevelFilter::Error, level_filter(2, true));
+    }
+
+    #[test]
+    fn parse_format_test() {
+        assert_eq!(Ok(LogFormat::Json), LogFormat::parse("JSON"));
+        assert_eq!(Ok(LogFormat::Text), LogFormat::parse("text"));
+        assert!(LogFormat::parse("xml").is_err());
+    }
+
+    #[test]
+    fn format_record_test() {
+        assert_eq!("WARN  stest_lib::config: Config request returned 503",
+                   format_record(LogFormat::Text, "", Level::Warn, "stest_lib::config", Some("main"),
+                                 "Config request returned 503"));
+        assert_eq!("DEBUG stest_lib [worker-2]: 1048576 bytes",
+                   format_record(LogFormat::Text, "", Level::Debug, "stest_lib", Some("worker-2"),
+                                 "1048576 bytes"));
+
+        let line = format_record(LogFormat::Json, "2018-03-01T10:00:00.000+0000", Level::Error,
+                                 "stest", None, "Cannot find \"server\"");
+        let json = Json::from_str(&line).unwrap();
+        assert_eq!(Some("ERROR"), json.find("level").and_then(|l| l.as_string()));
+        assert_eq!(Some("Cannot find \"server\""), json.find("message").and_then(|m| m.as_string()));
+        assert_eq!(Some(&Json::Null), json.find("thread"));
+    }
+
+    #[test]
+    fn held_lines_test() {
+        static HOLD: AtomicBool = AtomicBool::new(false);
+        let logger = StderrLogger::with_hold(LevelFilter::Warn, LogFormat::Text, &HOLD);
+        let record = |level: Level, message: &str| {
+            logger.log(&Record::builder().level(level).target("stest_lib::runner")
+                       .args(format_args!("{}", message)).build());
+        };
+        HOLD.store(true, Ordering::SeqCst);
+        record(Level::Error, "Cannot find any servers");
+        record(Level::Info, "Testing against 1234");
+        logger.flush();
+        {
+            let held = logger.held.lock().unwrap();
+            assert_eq!(1, held.lines.len());
+            assert!(held.lines[0].starts_with("ERROR stest_lib::runner"));
+            assert!(held.lines[0].ends_with("Cannot find any servers"));
+        }
+
+        for _ in 0..(MAX_HELD_LINES + 5) {
+            record(Level::Warn, "Timed out");
+        }
+        {
+            let mut held = logger.held.lock().unwrap();
+            assert_eq!(MAX_HELD_LINES, held.lines.len());
+            assert_eq!(6, held.dropped);
+            held.lines.truncate(1);
+        }
+
+        HOLD.store(false, Ordering::SeqCst);
+        logger.flush();
+        let held = logger.held.lock().unwrap();
+        assert!(held.lines.is_empty());
+        assert_eq!(0, held.dropped);
+    }
+
+    #[test]
+    fn is_timeout_test() {
+        assert!(is_timeout(&Error::new(ErrorKind::WouldBlock, "timed out")));
+        assert!(is_timeout(&Error::new(ErrorKind::TimedOut, "timed out")));
+        assert!(!is_timeout(&Error::new(ErrorKind::ConnectionReset, "reset")));
+    }
+}
@@ -1,200 +1,567 @@
-extern crate csv;
 #[macro_use]
 extern crate clap;
+#[macro_use]
 extern crate stest_lib;
+#[macro_use]
+extern crate log;
 
 mod args;
-use std::{thread, time};
+use std::{process, thread, time};
+use std::net::IpAddr;
+
+use clap::ArgMatches;
 
-use stest_lib::config;
-use stest_lib::config::{TestServerConfig, find_ignore_ids};
 use stest_lib::file_utils;
-use stest_lib::time_utils::get_current_time_as_string;
-use stest_lib::{find_best_server_by_ping, perform_download_test, perform_upload_test, pick_closest_servers, parse_url};
+use stest_lib::budget;
+use stest_lib::budget::DataBudget;
+use stest_lib::utils::parse_bytes;
+use stest_lib::units::{SpeedFormat, SpeedUnit};
+use stest_lib::results;
+use stest_lib::results::{RunSummary, TestResult};
+use stest_lib::output;
+use stest_lib::logging;
+use stest_lib::logging::LogFormat;
+use stest_lib::history;
+use stest_lib::history::{HistoryStore, HistoryFilter};
+use stest_lib::client::{ClientOptions, NetworkOptions, PingMethod, parse_header};
+use stest_lib::connector::IpVersion;
+use stest_lib::nagios;
+use stest_lib::settings::Settings;
+use stest_lib::ookla::Transport;
+use stest_lib::nagios::{Status, Threshold, Thresholds};
+use stest_lib::runner;
+use stest_lib::runner::{RunOptions, ServerStrategy};
+use stest_lib::CLOSEST_SERVERS;
+use stest_lib::daemon;
+use stest_lib::daemon::Daemon;
+use stest_lib::progress;
+use stest_lib::progress::Progress;
+use stest_lib::tui::Tui;
+
+const MIN_INTERVAL_SECS: u64 = 60;
+
+
+fn run_test(options: &RunOptions, file_name: Option<&str>, json_file_name: Option<&str>,
+            history: Option<&HistoryStore>, tui: bool, simple: bool) -> Result<RunSummary, String> {
+    let dashboard = if tui { Some(Tui::start(&options.client.progress, options.speed_format)) } else { None };
+    let outcome = runner::run(options, history);
+    if let Some(d) = dashboard {
+        d.stop();
+    }
 
-const MAX_NUM_RETRIES: u64      = 10;
-const ONE_SEC_IN_MILLIS: u64    = 1000;
+    let outcome = match outcome {
+        Ok(o)   => o,
+        Err(e)  => {
+            error!("{}", e);
+            return Err(e);
+        }
+    };
 
-const CSV_COLUMN_NAMES: &'static str = "test_number,server_url,rx_start,rx_total_bytes,rx_total_millis,rx_speed_mbps,rx_end,tx_start,tx_total_bytes,tx_total_millis,tx_speed_mbps,tx_end";
+    // In monitoring mode stdout only gets the status line (with the summary as perfdata)
+    if simple {
+        print_simple(&outcome.results, &options.speed_format);
+    } else if options.number_of_tests > 1 && !output::is_results_only() {
+        print_summary(&outcome.summary, &options.speed_format);
+    }
 
+    match file_name {
+        Some(f)     => {
+            file_utils::write_to_file(results::to_csv_string(&outcome.results, &outcome.summary, &options.speed_format), f);
+            chatter!("Finished writing to csv file {}", f);
+        }
+        None        => {}
+    }
 
-fn run_test(number_of_tests: u64, file_name: Option<&str>,
-            server_country: Option<&str>,
-            server_country_code: Option<&str>,
-            use_cached_servers: bool) {
-    // The speed test config file request returns nothing sometimes, but it looks like a
-    // glitch on the server side as similar content-length:0 responses come back when queried
-    // using curl as well. To work around it we retry upto MAX_NUM_RETRIES, it should come in
-    // via passed in args as well.
-    let mut current_count = 0;
-    let mut config = config::FullConfig::new();
+    match json_file_name {
+        Some(f)     => {
+            file_utils::write_json_to_file(results::to_json_string(&outcome.results, &outcome.summary, &options.speed_format), f);
+            chatter!("Finished writing to json file {}", f);
+        }
+        None        => {}
+    }
+
+    Ok(outcome.summary)
+}
 
-    while !config.parsing_succeeded && current_count <= MAX_NUM_RETRIES {
-        println!("Retrying...");
-        config = config::FullConfig::new();
-        thread::sleep(time::Duration::from_millis(ONE_SEC_IN_MILLIS));
-        current_count += 1;
+
+fn print_summary(summary: &RunSummary, format: &SpeedFormat) {
+    println!("");
+    println!("Summary over {} tests", summary.num_tests);
+    println!("{:<16}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}", "", "min", "max", "mean", "median", "p90", "stddev");
+    let rows = vec![(format.heading("Download"), format.convert_summary(&summary.rx_speed_mbps), summary.num_downloads),
+                    (format.heading("Upload"), format.convert_summary(&summary.tx_speed_mbps), summary.num_uploads),
+                    ("Latency (ms)".to_string(), summary.latency_millis.clone(), summary.num_pings)];
+    let precision = format.precision.unwrap_or(2);
+    // Phases skipped (or pings failed) by every test have nothing to show
+    for (name, s, _) in rows.into_iter().filter(|&(_, _, n)| n > 0) {
+        println!("{:<16}{:>10.*}{:>10.*}{:>10.*}{:>10.*}{:>10.*}{:>10.*}",
+                 name, precision, s.min, precision, s.max, precision, s.mean, precision, s.median,
+                 precision, s.p90, precision, s.stddev);
     }
+}
 
-//    println!("{:?}", config);
-    // TODO: Add a check to exit if we cannot retrieve any config
 
-    let mut test_servers: Vec<TestServerConfig> = config::get_all_test_servers(use_cached_servers);
-    println!("Total servers available: {:?}", test_servers.len());
+/// Same lines as speedtest-cli --simple for every test, skipped phases and failed pings
+/// are left out
+fn print_simple(results: &[TestResult], format: &SpeedFormat) {
+    let unit = match format.unit {
+        SpeedUnit::Kbps => "kbit/s",
+        SpeedUnit::Mbps => "Mbit/s",
+        SpeedUnit::Gbps => "Gbit/s",
+        u               => u.label()
+    };
+    for r in results {
+        if !r.ping_failed {
+            println!("Ping: {} ms", r.latency_millis);
+        }
+        if !r.rx_skipped {
+            println!("Download: {} {}", format.value(r.rx_speed_mbps), unit);
+        }
+        if !r.tx_skipped {
+            println!("Upload: {} {}", format.value(r.tx_speed_mbps), unit);
+        }
+    }
+}
 
-    let server_hint_config = config.server;
 
-    // Use find_ignore_ids from config mod
-    let ignore_ids = find_ignore_ids(server_hint_config.ignoreids);
-//    println!("Ignored ids: {:?}", ignore_ids);
+fn show_history(matches: &ArgMatches, db_path: &str, format: &SpeedFormat) {
+    let store = match HistoryStore::open(db_path) {
+        Ok(store)   => store,
+        Err(e)      => {
+            println!("Cannot open history database {} - {}", db_path, e);
+            return;
+        }
+    };
 
-    // ignore servers on ignore list
-    // TODO: Pass in argument to switch off ignore servers recommended by speedtest config?
-    test_servers.retain(|ref mut server| {
-        // If not ignore ids list keep this server
-        !ignore_ids.contains(&server.id)
-    });
+    let filter = HistoryFilter {
+        from_date: matches.value_of("from").map(|d| d.to_string()),
+        to_date: matches.value_of("to").map(|d| d.to_string()),
+        server: matches.value_of("server").map(|s| s.to_string()),
+        limit: matches.value_of("limit").and_then(|l| l.parse::<u64>().ok())
+    };
 
-    println!("Total servers available after ignoring: {:?}", test_servers.len());
-    println!("");
+    if matches.is_present("summary") {
+        match store.summarise(&filter) {
+            Ok(summary) => {
+                println!("Number of tests: {}", summary.num_tests);
+                println!("{:<16}{:>10}{:>10}{:>10}{:>10}", "", "mean", "p50", "p90", "p95");
+                println!("{:<16}{:>10.2}{:>10.2}{:>10.2}{:>10.2}", "Latency (ms)", summary.latency_millis.mean,
+                         summary.latency_millis.p50, summary.latency_millis.p90, summary.latency_millis.p95);
+                let rows = vec![(format.heading("Download"), &summary.rx_speed_mbps),
+                                (format.heading("Upload"), &summary.tx_speed_mbps)];
+                for (name, p) in rows {
+                    println!("{:<16}{:>10}{:>10}{:>10}{:>10}", name, format.value(p.mean), format.value(p.p50),
+                             format.value(p.p90), format.value(p.p95));
+                }
+                println!("");
+                println!("Daily averages");
+                println!("{:<12}{:>8}{:>14}{:>17}{:>15}", "date", "tests", "latency (ms)", format.heading("download"),
+                         format.heading("upload"));
+                for day in summary.daily {
+                    println!("{:<12}{:>8}{:>14.2}{:>17}{:>15}", day.date, day.num_tests,
+                             day.latency_millis, format.value(day.rx_speed_mbps), format.value(day.tx_speed_mbps));
+                }
+            },
+            Err(e)      => println!("Failed to summarise history - {}", e)
+        }
+    } else {
+        match store.query(&filter) {
+            Ok(results) => {
+                println!("{:<21}{:>8}  {:<30}{:>14}{:>17}{:>15}  {}", "tested at (UTC)", "server", "name",
+                         "latency (ms)", format.heading("download"), format.heading("upload"), "source");
+                for r in results {
+                    let latency = if r.ping_failed { "failed".to_string() } else { r.latency_millis.to_string() };
+                    println!("{:<21}{:>8}  {:<30}{:>14}{:>17}{:>15}  {}", r.tested_at, r.server_id,
+                             r.server_name, latency, format.value(r.rx_speed_mbps),
+                             format.value(r.tx_speed_mbps), r.source);
+                }
+            },
+            Err(e)      => println!("Failed to query history - {}", e)
+        }
+    }
+}
 
-    let closest_servers: Vec<TestServerConfig> = match server_country {
-        Some(sc)    => {
-            test_servers.retain(|ref mut server| {
-                // If not ignore ids list keep this server
-                server.country.to_lowercase() == sc.to_string().to_lowercase()
-            });
 
-            if test_servers.len() > 10 {
-                println!("Number of servers in {} are {} - it might take a while to find best server", sc, test_servers.len());
-            } else {
-                println!("Number of servers in {} are {}", sc, test_servers.len());
-            }
+fn parse_client_options(matches: &Settings) -> Result<ClientOptions, String> {
+    let mut headers = Vec::new();
+    for h in matches.values_of("header") {
+        headers.push(parse_header(&h)?);
+    }
 
-            test_servers
+    let source_address = match matches.value_of("source") {
+        Some(s) => Some(s.parse::<IpAddr>().map_err(|_| format!("Invalid source address '{}'", s))?),
+        None    => None
+    };
+
+    let ip_version = if matches.is_present("ipv4") {
+        IpVersion::V4
+    } else if matches.is_present("ipv6") {
+        IpVersion::V6
+    } else {
+        IpVersion::Any
+    };
+
+    let connections = match matches.value_of("connections") {
+        Some(c) => match c.parse::<usize>() {
+            Ok(n) if n > 0  => n,
+            _               => return Err(format!("Invalid number of connections '{}'", c))
         },
-        None        => {
-            match server_country_code {
-                Some(scc) => {
-                    test_servers.retain(|ref mut server| {
-                        // If not ignore ids list keep this server
-                        server.country_code.to_lowercase() == scc.to_string().to_lowercase()
-                    });
-
-                    if test_servers.len() > 10 {
-                        println!("Number of servers in {} are {} - it might take a while to find best server", scc, test_servers.len());
-                    } else {
-                        println!("Number of servers in {} are {}", scc, test_servers.len());
-                    }
-
-                    test_servers
-                },
-                None => {
-                    // both server country and server country code are not set.
-                    // look for closest servers - we should add a switch to avoid this distance check
-                    let client_conf = &config.client;
-                    let client_location = (client_conf.lat, client_conf.lon);
-                    let mut closest_servers: Vec<TestServerConfig> = Vec::new();
-                    pick_closest_servers(client_location, &test_servers, &mut closest_servers);
-                    closest_servers
-                }
-            }
+        None    => 0
+    };
+
+    let mut network = NetworkOptions::default();
+    if let Some(timeout) = parse_secs(matches, "connect_timeout")? {
+        network.connect_timeout = timeout;
+    }
+    if let Some(timeout) = parse_secs(matches, "timeout")? {
+        network.io_timeout = timeout;
+    }
+    if let Some(timeout) = parse_secs(matches, "upload_timeout")? {
+        network.upload_io_timeout = timeout;
+    }
+    if let Some(cutoff) = parse_secs(matches, "download_cutoff")? {
+        network.download_cutoff = cutoff;
+    }
+    if let Some(bytes) = matches.value_of("max_bytes") {
+        match parse_bytes(&bytes)? {
+            0   => return Err("Invalid value '0' for --max-bytes".to_string()),
+            b   => network.max_bytes = Some(b)
         }
+    }
+    if let Some(retries) = parse_number(matches, "retries")? {
+        network.retries = retries;
+    }
+    if let Some(millis) = parse_number(matches, "retry_delay")? {
+        network.retry_delay = time::Duration::from_millis(millis);
+    }
+
+    let ping_method = match matches.value_of("ping_method") {
+        Some(m) => PingMethod::parse(&m)?,
+        None    => PingMethod::Http
     };
 
+    let transport = match matches.value_of("transport") {
+        Some(t) => Transport::parse(&t)?,
+        None    => Transport::Http
+    };
 
-    println!("Your address {:?} and ISP {:?}", config.client.ip, config.client.isp);
-    if closest_servers.len() > 0 {
-        // TODO: May be change the server for each test?
-        // look for ping latency for all servers (or closest servers)
-        let (best_server, _) = find_best_server_by_ping(&closest_servers);
-        let mut records = Vec::new();
-
-        let mut col_names = Vec::new();
-        for name in CSV_COLUMN_NAMES.split(',') {
-            col_names.push(name.to_string());
-        }
-
-        let sizes: Vec<u64> = vec![32768, 65536, 131072, 262144, 524288, 1048576, 7340032];
-        let dimensions: Vec<u64> = vec![350, 500, 750, 1000, 1500, 2000, 2500, 3000];
-
-        records.push(col_names);
-
-        for i in 0..number_of_tests {
-            let current_test = i + 1;
-            println!("Performing test {}", current_test);
-            let mut record = Vec::new();
-            let server_url = parse_url(&best_server.url);
-            record.push(current_test.to_string());
-            record.push(server_url.to_owned());
-
-            // Start tests against chosen server - these download/upload tests will
-            // run in separate threads
-            print!("Running download tests...");
-            record.push(get_current_time_as_string());
-            let (rx_total_bytes, rx_total_millis, rx_speed_in_mbps) = perform_download_test(&server_url, &dimensions);
-            record.push(rx_total_bytes.to_string());
-            record.push(rx_total_millis.to_string());
-            record.push(rx_speed_in_mbps.to_string());
-            record.push(get_current_time_as_string());
-            println!("");
-
-            print!("Running upload tests...");
-            record.push(get_current_time_as_string());
-            let (tx_total_bytes, tx_total_millis, tx_speed_in_mbps) = perform_upload_test(
-                &best_server.url,
-                &config.upload,
-                &sizes);
-            record.push(tx_total_bytes.to_string());
-            record.push(tx_total_millis.to_string());
-            record.push(tx_speed_in_mbps.to_string());
-            record.push(get_current_time_as_string());
-            // run a HTTP server in probably main thread and do the rest in separate thread.
-            println!("Done");
-            records.push(record);
-        }
-
-        match file_name {
-            Some(f)     => {
-                let mut writer = csv::Writer::from_memory();
-                for record in records {
-                    writer.encode(record);
-                }
+    Ok(ClientOptions {
+        https: matches.is_present("https"),
+        proxy: matches.value_of("proxy"),
+        no_proxy: matches.value_of("no_proxy")
+            .map(|hosts| hosts.split(',').map(|h| h.trim().to_string()).collect())
+            .unwrap_or(Vec::new()),
+        user_agent: matches.value_of("user_agent"),
+        headers: headers,
+        source_address: source_address,
+        interface: matches.value_of("interface"),
+        ip_version: ip_version,
+        connections: connections,
+        network: network,
+        ping_method: ping_method,
+        transport: transport,
+        progress: Progress::new()
+    })
+}
 
-                // println!("{}", writer.into_string());
-                file_utils::write_to_file(writer.into_string(), f);
-                println!("Finished writing to csv file {}", f);
-            }
-            None        => {}
+
+fn parse_number(matches: &Settings, name: &str) -> Result<Option<u64>, String> {
+    match matches.value_of(name) {
+        Some(v) => v.parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("Invalid value '{}' for --{}", v, name.replace("_", "-"))),
+        None    => Ok(None)
+    }
+}
+
+
+/// Runs closer together than a minute would keep the link saturated
+fn parse_interval(matches: &Settings) -> Result<Option<u64>, String> {
+    match parse_number(matches, "interval")? {
+        Some(secs) if secs < MIN_INTERVAL_SECS  => Err(format!("Invalid value '{}' for --interval, it should be at least {} seconds",
+                                                               secs, MIN_INTERVAL_SECS)),
+        interval                                => Ok(interval)
+    }
+}
+
+
+/// Timeouts of 0 are rejected by sockets so every request would fail
+fn parse_secs(matches: &Settings, name: &str) -> Result<Option<time::Duration>, String> {
+    match parse_number(matches, name)? {
+        Some(0)     => Err(format!("Invalid value '0' for --{}", name.replace("_", "-"))),
+        Some(secs)  => Ok(Some(time::Duration::from_secs(secs))),
+        None        => Ok(None)
+    }
+}
+
+
+fn parse_speed_format(matches: &Settings) -> Result<SpeedFormat, String> {
+    let unit = match matches.value_of("units") {
+        Some(u) => SpeedUnit::parse(&u)?,
+        None    => SpeedUnit::Mbps
+    };
+    Ok(SpeedFormat { unit: unit, precision: parse_number(matches, "precision")?.map(|p| p as usize) })
+}
+
+
+fn parse_server_ids(matches: &Settings) -> Result<Vec<u64>, String> {
+    match matches.value_of("compare_ids") {
+        Some(ids)   => ids.split(',')
+            .map(|id| id.trim().parse::<u64>().map_err(|_| format!("Invalid server id '{}'", id)))
+            .collect(),
+        None        => Ok(Vec::new())
+    }
+}
+
+
+fn parse_thresholds(matches: &Settings) -> Result<Thresholds, String> {
+    let parse = |name: &str, parse_threshold: fn(&str) -> Result<Threshold, String>| -> Result<Option<Threshold>, String> {
+        match matches.value_of(name) {
+            Some(v) => parse_threshold(&v).map(Some),
+            None    => Ok(None)
         }
+    };
 
-    } else {
-        println!("Cannot find any servers, please note that if you're searching by country name currently it is an exact match.");
+    Ok(Thresholds {
+        min_download: parse("min_download", Threshold::parse_min)?,
+        min_upload: parse("min_upload", Threshold::parse_min)?,
+        max_latency: parse("max_latency", Threshold::parse_max)?
+    })
+}
+
+
+/// Prints plugin output when thresholds are set. Without thresholds we still return
+/// UNKNOWN if tests could not be run, so scripts can tell a failed run apart.
+fn check_thresholds(summary: Result<&RunSummary, &String>, thresholds: &Thresholds) -> Status {
+    match summary {
+        Ok(s)   => {
+            if thresholds.is_empty() {
+                Status::Ok
+            } else {
+                let (status, output) = thresholds.check(s);
+                println!("{}", output);
+                status
+            }
+        },
+        Err(e)  => {
+            if !thresholds.is_empty() {
+                println!("{}", nagios::unknown(e));
+            }
+            Status::Unknown
+        }
     }
+}
+
 
+/// Log level from -v (repeated) or a `verbose = <n>` config file entry, -q wins
+fn init_logging(settings: &Settings) -> Result<(), String> {
+    let verbosity = match settings.occurrences_of("verbose") {
+        0   => match settings.value_of("verbose") {
+            Some(v) => v.parse::<u64>().map_err(|_| format!("Invalid value '{}' for verbose", v))?,
+            None    => 0
+        },
+        n   => n
+    };
+    let format = match settings.value_of("log_format") {
+        Some(f) => LogFormat::parse(&f)?,
+        None    => LogFormat::default()
+    };
+    logging::init(logging::level_filter(verbosity, settings.is_present("quiet")), format)
 }
 
 
 fn main() {
-    println!("");
-
     let matches = args::parse_args();
-    let number_of_tests = matches.value_of("number_tests");
-    let csv_file_name = matches.value_of("csv");
-    let server_country = matches.value_of("server-country");
-    let server_country_code = matches.value_of("server-country-code");
-    let use_cached_servers = matches.is_present("use_cached");
+    let settings = match Settings::load(&matches).and_then(|s| s.validate(args::app()).map(|_| s)) {
+        Ok(s)   => s,
+        Err(e)  => {
+            println!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+    if let Err(e) = init_logging(&settings) {
+        println!("{}", e);
+        process::exit(Status::Unknown.exit_code());
+    }
+    let monitoring = ["min_download", "min_upload", "max_latency"].iter().any(|t| settings.is_present(t));
+    output::set_results_only(settings.is_present("simple") || monitoring);
+    output::set_quiet(settings.is_present("quiet"));
+    chatter!("");
+    let history_db_path = settings.value_of("history_db")
+        .unwrap_or_else(history::default_db_path);
+
+    let speed_format = match parse_speed_format(&settings) {
+        Ok(f)   => f,
+        Err(e)  => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+
+    if let Some(history_matches) = matches.subcommand_matches("history") {
+        show_history(history_matches, &history_db_path, &speed_format);
+        return;
+    }
+
+    let csv_file_name = settings.value_of("csv");
+    let json_file_name = settings.value_of("json");
+    let client_options = match parse_client_options(&settings) {
+        Ok(o)   => o,
+        Err(e)  => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
 
     let mut n_tests: u64 = 1;
 
-    if let Some(n) = number_of_tests {
+    if let Some(n) = settings.value_of("number_tests") {
         // Any non-numerical number of tests will default to 1 test
         let num_tests: u64 = n.parse::<u64>().unwrap_or(1);
         n_tests = num_tests;
     }
 
-    println!("Number of tests to run {}", n_tests);
-//    println!("CSV file name {:?}", csv_file_name);
-//    println!("Server country - {:?} code - {:?}", server_country, server_country_code);
-    run_test(n_tests, csv_file_name, server_country, server_country_code, use_cached_servers);
+    let compare = match parse_number(&settings, "compare") {
+        Ok(Some(n)) if n > 0    => n as usize,
+        Ok(Some(_))             => {
+            error!("Invalid value '0' for --compare");
+            process::exit(Status::Unknown.exit_code());
+        },
+        Ok(None)                => 1,
+        Err(e)                  => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+
+    let compare_ids = match parse_server_ids(&settings) {
+        Ok(ids) => ids,
+        Err(e)  => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+
+    let server_strategy = match settings.value_of("server_strategy") {
+        Some(s) => match ServerStrategy::parse(&s) {
+            Ok(strategy)    => strategy,
+            Err(e)          => {
+                error!("{}", e);
+                process::exit(Status::Unknown.exit_code());
+            }
+        },
+        None    => ServerStrategy::Fixed
+    };
+
+    let top_k = match parse_number(&settings, "top_k") {
+        Ok(Some(k)) if k > 0    => k as usize,
+        Ok(Some(_))             => {
+            error!("Invalid value '0' for --top-k");
+            process::exit(Status::Unknown.exit_code());
+        },
+        Ok(None)                => CLOSEST_SERVERS,
+        Err(e)                  => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+
+    let budget = match settings.value_of("monthly_budget").map(|b| parse_bytes(&b)) {
+        Some(Ok(limit)) => {
+            let usage_file = settings.value_of("usage_file").unwrap_or_else(budget::default_usage_path);
+            Some(DataBudget::new(&usage_file, limit))
+        },
+        Some(Err(e))    => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        },
+        None            => None
+    };
+
+    let run_options = RunOptions {
+        number_of_tests: n_tests,
+        server_country: settings.value_of("server-country"),
+        server_country_code: settings.value_of("server-country-code"),
+        use_cached_servers: settings.is_present("use_cached"),
+        speedtest_config: settings.value_of("speedtest_config"),
+        dual_stack: settings.is_present("dual_stack"),
+        loaded_latency: !settings.is_present("no_loaded_latency"),
+        download: !settings.is_present("no_download") && !settings.is_present("ping_only"),
+        upload: !settings.is_present("no_upload") && !settings.is_present("ping_only"),
+        compare: compare,
+        compare_ids: compare_ids,
+        server_strategy: server_strategy,
+        top_k: top_k,
+        budget: budget,
+        speed_format: speed_format,
+        client: client_options
+    };
+
+    if let Some(address) = settings.value_of("progress_listen") {
+        match progress::serve(&address, run_options.client.progress.clone()) {
+            Ok(a)   => chatter!("Streaming progress to WebSocket clients on ws://{}", a),
+            Err(e)  => {
+                error!("{}", e);
+                process::exit(Status::Unknown.exit_code());
+            }
+        }
+    }
+
+    if let Some(daemon_matches) = matches.subcommand_matches("daemon") {
+        let history_db = if settings.is_present("no_history") { None } else { Some(history_db_path) };
+        let daemon = Daemon::new(run_options, history_db,
+                                 daemon_matches.value_of("allow_origin").map(|o| o.to_string()));
+        let address = daemon_matches.value_of("listen").unwrap_or(daemon::DEFAULT_LISTEN_ADDRESS);
+        if let Err(e) = daemon::serve(address, daemon) {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+        return;
+    }
+
+    let thresholds = match parse_thresholds(&settings) {
+        Ok(t)   => t,
+        Err(e)  => {
+            println!("{}", nagios::unknown(&e));
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+
+    let interval = match parse_interval(&settings) {
+        Ok(i)   => i,
+        Err(e)  => {
+            error!("{}", e);
+            process::exit(Status::Unknown.exit_code());
+        }
+    };
+
+    let history_store = if settings.is_present("no_history") {
+        None
+    } else {
+        match HistoryStore::open(&history_db_path) {
+            Ok(store)   => Some(store),
+            Err(e)      => {
+                warn!("Cannot open history database {}, results will not be stored - {}", history_db_path, e);
+                None
+            }
+        }
+    };
+
+    loop {
+        chatter!("Number of tests to run {}", n_tests);
+//        println!("CSV file name {:?}", csv_file_name);
+//        println!("Server country - {:?} code - {:?}", server_country, server_country_code);
+        let summary = run_test(&run_options, csv_file_name.as_ref().map(|f| f.as_str()),
+                               json_file_name.as_ref().map(|f| f.as_str()),
+                               history_store.as_ref(), settings.is_present("tui"), settings.is_present("simple"));
+        let status = check_thresholds(summary.as_ref(), &thresholds);
+
+        match interval {
+            Some(secs)  => {
+                chatter!("Next run in {} seconds", secs);
+                thread::sleep(time::Duration::from_secs(secs));
+            },
+            None        => process::exit(status.exit_code())
+        }
+    }
 }
@@ -0,0 +1,338 @@
+use results::RunSummary;
+
+const SERVICE_NAME: &'static str = "SPEEDTEST";
+
+
+/// Plugin states as understood by Nagios/Icinga, the exit code is what the
+/// monitoring system uses to decide the state of the service.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Status {
+    Ok,
+    Warning,
+    Critical,
+    Unknown
+}
+
+
+impl Status {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Status::Ok          => 0,
+            Status::Warning     => 1,
+            Status::Critical    => 2,
+            Status::Unknown     => 3
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Status::Ok          => "OK",
+            Status::Warning     => "WARNING",
+            Status::Critical    => "CRITICAL",
+            Status::Unknown     => "UNKNOWN"
+        }
+    }
+}
+
+
+/// Warning and critical levels given on command line as "warning,critical". A single
+/// value is used for both levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    pub warning: f64,
+    pub critical: f64
+}
+
+
+impl Threshold {
+    pub fn parse(value: &str) -> Result<Threshold, String> {
+        let levels: Vec<&str> = value.split(',').map(|v| v.trim()).collect();
+        let parse_level = |level: &str| {
+            level.parse::<f64>().map_err(|_| format!("Invalid threshold value '{}' in '{}'", level, value))
+        };
+
+        match levels.len() {
+            1 => {
+                let level = parse_level(levels[0])?;
+                Ok(Threshold { warning: level, critical: level })
+            },
+            2 => {
+                Ok(Threshold { warning: parse_level(levels[0])?, critical: parse_level(levels[1])? })
+            },
+            _ => Err(format!("Threshold '{}' should be given as warning,critical", value))
+        }
+    }
+
+    /// Threshold of a value that must stay above it, so critical cannot be above warning
+    pub fn parse_min(value: &str) -> Result<Threshold, String> {
+        let threshold = Threshold::parse(value)?;
+        if threshold.critical > threshold.warning {
+            return Err(format!("Critical level of minimum threshold '{}' should not be above the warning level", value));
+        }
+        Ok(threshold)
+    }
+
+    /// Threshold of a value that must stay below it, so critical cannot be below warning
+    pub fn parse_max(value: &str) -> Result<Threshold, String> {
+        let threshold = Threshold::parse(value)?;
+        if threshold.critical < threshold.warning {
+            return Err(format!("Critical level of maximum threshold '{}' should not be below the warning level", value));
+        }
+        Ok(threshold)
+    }
+
+    /// Status when `value` must stay above the threshold e.g. download speed
+    pub fn check_min(&self, value: f64) -> Status {
+        if value < self.critical {
+            Status::Critical
+        } else if value < self.warning {
+            Status::Warning
+        } else {
+            Status::Ok
+        }
+    }
+
+    /// Status when `value` must stay below the threshold e.g. latency
+    pub fn check_max(&self, value: f64) -> Status {
+        if value > self.critical {
+            Status::Critical
+        } else if value > self.warning {
+            Status::Warning
+        } else {
+            Status::Ok
+        }
+    }
+}
+
+
+#[derive(Debug, Default, Clone)]
+pub struct Thresholds {
+    pub min_download: Option<Threshold>,
+    pub min_upload: Option<Threshold>,
+    pub max_latency: Option<Threshold>
+}
+
+
+impl Thresholds {
+    pub fn is_empty(&self) -> bool {
+        self.min_download.is_none() && self.min_upload.is_none() && self.max_latency.is_none()
+    }
+
+    /// Checks mean values of the run against thresholds, returns overall status and
+    /// the one line plugin output with perfdata.
+    pub fn check(&self, summary: &RunSummary) -> (Status, String) {
+        let download = summary.rx_speed_mbps.mean;
+        let upload = summary.tx_speed_mbps.mean;
+        let latency = summary.latency_millis.mean;
+
+        let mut status = Status::Ok;
+        let mut problems: Vec<String> = Vec::new();
+
+        // Speed thresholds cannot be checked when every test skipped the phase
+        if let Some(ref t) = self.min_download {
+            let s = if summary.num_downloads == 0 { Status::Unknown } else { t.check_min(download) };
+            match s {
+                Status::Ok      => {},
+                Status::Unknown => problems.push("download not tested".to_string()),
+                _               => problems.push(format!("download {:.2} Mbps < {:.2}", download, level_for(t, s)))
+            }
+            status = worst(status, s);
+        }
+
+        if let Some(ref t) = self.min_upload {
+            let s = if summary.num_uploads == 0 { Status::Unknown } else { t.check_min(upload) };
+            match s {
+                Status::Ok      => {},
+                Status::Unknown => problems.push("upload not tested".to_string()),
+                _               => problems.push(format!("upload {:.2} Mbps < {:.2}", upload, level_for(t, s)))
+            }
+            status = worst(status, s);
+        }
+
+        // No server answering the ping is as bad as it gets
+        if let Some(ref t) = self.max_latency {
+            let s = if summary.num_pings == 0 { Status::Critical } else { t.check_max(latency) };
+            match s {
+                Status::Ok                              => {},
+                _ if summary.num_pings == 0             => problems.push("ping failed".to_string()),
+                _                                       => problems.push(format!("latency {:.0} ms > {:.0}", latency, level_for(t, s)))
+            }
+            status = worst(status, s);
+        }
+
+        let mut measured: Vec<String> = Vec::new();
+        let mut perfdata_values: Vec<String> = Vec::new();
+        if summary.num_downloads > 0 {
+            measured.push(format!("Download {:.2} Mbps", download));
+            perfdata_values.push(perfdata("download_mbps", download, "", &self.min_download, 1.0, true));
+        }
+        if summary.num_uploads > 0 {
+            measured.push(format!("Upload {:.2} Mbps", upload));
+            perfdata_values.push(perfdata("upload_mbps", upload, "", &self.min_upload, 1.0, true));
+        }
+        if summary.num_pings > 0 {
+            measured.push(format!("Latency {:.0} ms", latency));
+            perfdata_values.push(perfdata("latency", latency / 1000.0, "s", &self.max_latency, 1000.0, false));
+        }
+
+        let message = match (measured.is_empty(), problems.is_empty()) {
+            (_, true)       => measured.join(", "),
+            (true, false)   => problems.join(", "),
+            (false, false)  => format!("{} ({})", measured.join(", "), problems.join(", "))
+        };
+
+        let mut output = format!("{} {} - {}", SERVICE_NAME, status.label(), message);
+        if !perfdata_values.is_empty() {
+            output = format!("{} | {}", output, perfdata_values.join(" "));
+        }
+        (status, output)
+    }
+}
+
+
+/// Plugin output when the test could not be run at all
+pub fn unknown(message: &str) -> String {
+    format!("{} {} - {}", SERVICE_NAME, Status::Unknown.label(), message)
+}
+
+
+fn worst(current: Status, other: Status) -> Status {
+    if other > current { other } else { current }
+}
+
+
+fn level_for(threshold: &Threshold, status: Status) -> f64 {
+    if status == Status::Critical { threshold.critical } else { threshold.warning }
+}
+
+
+/// Perfdata in 'label'=value[UOM];[warn];[crit];[min];[max] format, `divisor` converts
+/// thresholds into the unit of the value. In Nagios range syntax a plain level alerts
+/// above it, levels of minimum thresholds are written as "50:" to alert below them.
+fn perfdata(label: &str, value: f64, uom: &str, threshold: &Option<Threshold>, divisor: f64,
+            minimum: bool) -> String {
+    let range = |level: f64| if minimum { format!("{}:", level / divisor) } else { format!("{}", level / divisor) };
+    let (warning, critical) = match *threshold {
+        Some(ref t) => (range(t.warning), range(t.critical)),
+        None        => (String::new(), String::new())
+    };
+    format!("{}={:.3}{};{};{};0;", label, value, uom, warning, critical)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Threshold, Thresholds, Status, unknown};
+    use results::RunSummary;
+    use stats::Summary;
+
+    fn summary(download: f64, upload: f64, latency: f64) -> RunSummary {
+        RunSummary {
+            num_tests: 1,
+            num_downloads: 1,
+            num_uploads: 1,
+            num_pings: 1,
+            rx_speed_mbps: Summary { mean: download, ..Summary::default() },
+            tx_speed_mbps: Summary { mean: upload, ..Summary::default() },
+            latency_millis: Summary { mean: latency, ..Summary::default() }
+        }
+    }
+
+    #[test]
+    fn parse_threshold_test() {
+        assert_eq!(Threshold { warning: 50.0, critical: 20.0 }, Threshold::parse("50,20").unwrap());
+        assert_eq!(Threshold { warning: 10.0, critical: 10.0 }, Threshold::parse("10").unwrap());
+        assert!(Threshold::parse("abc").is_err());
+        assert!(Threshold::parse("1,2,3").is_err());
+    }
+
+    #[test]
+    fn parse_threshold_order_test() {
+        assert!(Threshold::parse_min("50,20").is_ok());
+        assert!(Threshold::parse_min("20,50").is_err());
+        assert!(Threshold::parse_max("100,200").is_ok());
+        assert!(Threshold::parse_max("200,100").is_err());
+        assert!(Threshold::parse_max("100").is_ok());
+    }
+
+    #[test]
+    fn check_min_max_test() {
+        let t = Threshold { warning: 50.0, critical: 20.0 };
+        assert_eq!(Status::Ok, t.check_min(60.0));
+        assert_eq!(Status::Warning, t.check_min(30.0));
+        assert_eq!(Status::Critical, t.check_min(10.0));
+
+        let t = Threshold { warning: 100.0, critical: 200.0 };
+        assert_eq!(Status::Ok, t.check_max(20.0));
+        assert_eq!(Status::Warning, t.check_max(150.0));
+        assert_eq!(Status::Critical, t.check_max(250.0));
+    }
+
+    #[test]
+    fn check_worst_status_wins_test() {
+        let thresholds = Thresholds {
+            min_download: Some(Threshold { warning: 50.0, critical: 20.0 }),
+            min_upload: Some(Threshold { warning: 5.0, critical: 1.0 }),
+            max_latency: Some(Threshold { warning: 100.0, critical: 200.0 })
+        };
+
+        let (status, output) = thresholds.check(&summary(90.0, 10.0, 20.0));
+        assert_eq!(Status::Ok, status);
+        assert!(output.starts_with("SPEEDTEST OK - Download 90.00 Mbps"));
+        assert!(output.contains("| download_mbps=90.000;50:;20:;0; upload_mbps=10.000;5:;1:;0; latency=0.020s;0.1;0.2;0;"));
+
+        let (status, _) = thresholds.check(&summary(30.0, 10.0, 250.0));
+        assert_eq!(Status::Critical, status);
+        assert_eq!(2, status.exit_code());
+
+        let (status, output) = thresholds.check(&summary(30.0, 10.0, 20.0));
+        assert_eq!(Status::Warning, status);
+        assert!(output.contains("download 30.00 Mbps < 50.00"));
+    }
+
+    #[test]
+    fn check_skipped_phases_test() {
+        let ping_only = RunSummary { num_downloads: 0, num_uploads: 0, ..summary(0.0, 0.0, 20.0) };
+        let latency_only = Thresholds {
+            max_latency: Some(Threshold { warning: 100.0, critical: 200.0 }),
+            ..Thresholds::default()
+        };
+        let (status, output) = latency_only.check(&ping_only);
+        assert_eq!(Status::Ok, status);
+        assert_eq!("SPEEDTEST OK - Latency 20 ms | latency=0.020s;0.1;0.2;0;", output);
+
+        let with_download = Thresholds { min_download: Some(Threshold { warning: 50.0, critical: 20.0 }), ..latency_only };
+        let (status, output) = with_download.check(&ping_only);
+        assert_eq!(Status::Unknown, status);
+        assert!(output.contains("download not tested"));
+    }
+
+    #[test]
+    fn check_failed_ping_test() {
+        let unanswered = RunSummary { num_pings: 0, ..summary(90.0, 10.0, 0.0) };
+        let thresholds = Thresholds {
+            max_latency: Some(Threshold { warning: 100.0, critical: 200.0 }),
+            ..Thresholds::default()
+        };
+        let (status, output) = thresholds.check(&unanswered);
+        assert_eq!(Status::Critical, status);
+        assert!(output.contains("(ping failed)"));
+        assert!(!output.contains("latency="));
+    }
+
+    #[test]
+    fn check_nothing_measured_test() {
+        let nothing = RunSummary { num_downloads: 0, num_uploads: 0, num_pings: 0, ..summary(0.0, 0.0, 0.0) };
+        let thresholds = Thresholds {
+            max_latency: Some(Threshold { warning: 100.0, critical: 200.0 }),
+            ..Thresholds::default()
+        };
+        assert_eq!((Status::Critical, "SPEEDTEST CRITICAL - ping failed".to_string()), thresholds.check(&nothing));
+    }
+
+    #[test]
+    fn unknown_test() {
+        assert_eq!("SPEEDTEST UNKNOWN - no servers", unknown("no servers"));
+        assert_eq!(3, Status::Unknown.exit_code());
+    }
+}
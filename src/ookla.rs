@@ -0,0 +1,410 @@
+use std::cmp::min;
+use std::io;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use client::ClientOptions;
+use config::{TestServerConfig, UploadConfig};
+use utils::compute_speed_in_mbps;
+use worker::WorkerPool;
+use progress::{ByteCounter, Phase};
+use logging;
+use {parse_host_port, average_latency};
+
+/// Bytes requested/sent by a single DOWNLOAD/UPLOAD command
+const CHUNK_SIZE: u64 = 1024 * 1024;
+const BUFFER_SIZE: usize = 8192;
+
+
+/// Which protocol download/upload tests and latency use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// Legacy latency.txt, random*.jpg and upload.php endpoints
+    Http,
+    /// Ookla TCP service on the host:port of the server list
+    Ookla
+}
+
+
+impl Transport {
+    pub fn parse(transport: &str) -> Result<Transport, String> {
+        match transport.to_lowercase().as_str() {
+            "http"  => Ok(Transport::Http),
+            "ookla" => Ok(Transport::Ookla),
+            _       => Err(format!("Invalid transport '{}', it should be http or ookla", transport))
+        }
+    }
+}
+
+
+impl Default for Transport {
+    fn default() -> Transport {
+        Transport::Http
+    }
+}
+
+
+/// Single connection to the Ookla TCP service. Commands are line based - HI, PING,
+/// DOWNLOAD n and UPLOAD n, where n is the number of bytes including the command line
+/// for uploads and the "DOWNLOAD " prefix for downloads.
+pub struct OoklaClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+
+impl OoklaClient {
+    /// Connects and greets the server with HI
+    pub fn connect(server: &TestServerConfig, options: &ClientOptions) -> io::Result<OoklaClient> {
+        let (host, port) = parse_host_port(&server.host);
+        let stream = options.connector().connect_tcp(&host, port)?;
+        stream.set_read_timeout(Some(options.network.io_timeout))?;
+        stream.set_write_timeout(Some(options.network.io_timeout))?;
+        stream.set_nodelay(true)?;
+
+        let mut client = OoklaClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream
+        };
+        let hello = client.command("HI")?;
+        if !hello.starts_with("HELLO") {
+            return Err(unexpected("HI", &hello));
+        }
+        Ok(client)
+    }
+
+    /// Round trip time of a single PING
+    pub fn ping(&mut self) -> io::Result<Duration> {
+        let start = Instant::now();
+        let pong = self.command(&format!("PING {}", ::ext_time::precise_time_ns() / 1_000_000))?;
+        if !pong.starts_with("PONG") {
+            return Err(unexpected("PING", &pong));
+        }
+        Ok(start.elapsed())
+    }
+
+    /// Downloads `size` bytes, stops early when `deadline` passes or `counter` reaches
+    /// `max_bytes` in which case the connection should not be used any more. Every
+    /// buffer read is added to `counter`. Returns bytes read.
+    pub fn download(&mut self, size: u64, deadline: Instant, counter: &ByteCounter,
+                    max_bytes: Option<u64>) -> io::Result<u64> {
+        write!(self.writer, "DOWNLOAD {}\n", size)?;
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut read_bytes: u64 = 0;
+
+        while read_bytes < size && Instant::now() < deadline {
+            let wanted = counter.reserve(min(BUFFER_SIZE as u64, size - read_bytes), max_bytes);
+            if wanted == 0 {
+                break;
+            }
+            let s = match self.reader.read(&mut buf[..wanted as usize]) {
+                Ok(s)   => s as u64,
+                Err(e)  => {
+                    counter.remove(wanted);
+                    return Err(e);
+                }
+            };
+            counter.remove(wanted - s);
+            if s == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed during DOWNLOAD"));
+            }
+            read_bytes = read_bytes + s;
+        }
+        Ok(read_bytes)
+    }
+
+    /// Uploads `size` bytes, stops early when `deadline` passes or `counter` reaches
+    /// `max_bytes` in which case the connection should not be used any more. Every
+    /// buffer written is added to `counter`. Returns bytes written.
+    pub fn upload(&mut self, size: u64, deadline: Instant, counter: &ByteCounter,
+                  max_bytes: Option<u64>) -> io::Result<u64> {
+        let header = format!("UPLOAD {} 0\n", size);
+        self.writer.write_all(header.as_bytes())?;
+        let mut written = header.len() as u64;
+        counter.add(written);
+
+        let buf = vec![b'0'; BUFFER_SIZE];
+        while written < size - 1 {
+            if Instant::now() >= deadline {
+                return Ok(written);
+            }
+            let remaining = counter.reserve(min(BUFFER_SIZE as u64, size - 1 - written), max_bytes);
+            if remaining == 0 {
+                return Ok(written);
+            }
+            self.writer.write_all(&buf[..remaining as usize])?;
+            written = written + remaining;
+        }
+        self.writer.write_all(b"\n")?;
+        written = written + 1;
+        counter.add(1);
+
+        let ok = self.read_line()?;
+        if !ok.starts_with("OK") {
+            return Err(unexpected("UPLOAD", &ok));
+        }
+        Ok(written)
+    }
+
+    pub fn quit(mut self) {
+        self.writer.write_all(b"QUIT\n").ok();
+    }
+
+    fn command(&mut self, command: &str) -> io::Result<String> {
+        write!(self.writer, "{}\n", command)?;
+        self.read_line()
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by server"));
+        }
+        Ok(line.trim().to_string())
+    }
+}
+
+
+/// Average PING round trip in millis over the answered ones of 3 pings on one
+/// connection, None when the server cannot be reached or answers none of them.
+pub fn measure_latency(server: &TestServerConfig, options: &ClientOptions) -> Option<u64> {
+    let mut client = match OoklaClient::connect(server, options) {
+        Ok(c)   => c,
+        Err(e)  => {
+            debug!("Cannot connect to {} - {}", server.host, e);
+            return None;
+        }
+    };
+
+    let mut answered: Vec<u64> = Vec::new();
+    for _ in 0..3 {
+        match client.ping() {
+            Ok(d)   => answered.push(d.as_secs() * 1_000 + (d.subsec_nanos() / 1_000_000) as u64),
+            Err(e)  => debug!("PING to {} failed - {}", server.host, e)
+        }
+    }
+    client.quit();
+    average_latency(&answered)
+}
+
+
+/// Every connection keeps downloading chunks until download cutoff of options passes
+pub fn perform_download_test(server: &TestServerConfig, options: &ClientOptions) -> (u64, u64, f64) {
+    let cutoff = options.network.download_cutoff;
+    let (total_bytes, elapsed_as_millis) = run_on_connections(server, options, Phase::Download, cutoff, |client, deadline, counter, max_bytes| {
+        client.download(CHUNK_SIZE, deadline, counter, max_bytes)
+    });
+
+    chatter!("Downloaded {} bytes in {}ms", total_bytes, elapsed_as_millis);
+    let speed_in_mbps = compute_speed_in_mbps(total_bytes, elapsed_as_millis);
+    (total_bytes, elapsed_as_millis, speed_in_mbps)
+}
+
+
+/// Every connection keeps uploading chunks for the test length of upload config
+pub fn perform_upload_test(server: &TestServerConfig, upload_conf: &UploadConfig,
+                           options: &ClientOptions) -> (u64, u64, f64) {
+    let test_length = if upload_conf.testlength > 0 {
+        Duration::from_secs(upload_conf.testlength)
+    } else {
+        options.network.download_cutoff
+    };
+    let (total_bytes, elapsed_as_millis) = run_on_connections(server, options, Phase::Upload, test_length, |client, deadline, counter, max_bytes| {
+        client.upload(CHUNK_SIZE, deadline, counter, max_bytes)
+    });
+
+    chatter!("Uploaded {} bytes in {}ms", total_bytes, elapsed_as_millis);
+    let speed_in_mbps = compute_speed_in_mbps(total_bytes, elapsed_as_millis);
+    (total_bytes, elapsed_as_millis, speed_in_mbps)
+}
+
+
+/// Runs `transfer` over and over on options.connections() connections until
+/// `test_length` passes or max bytes are transferred, returns total bytes and elapsed
+/// millis. `transfer` adds what it transfers to the counter of the phase and stops
+/// within a buffer once max bytes are reached.
+fn run_on_connections<F>(server: &TestServerConfig, options: &ClientOptions, phase: Phase,
+                         test_length: Duration, transfer: F) -> (u64, u64)
+    where F: Fn(&mut OoklaClient, Instant, &ByteCounter, Option<u64>) -> io::Result<u64> + Send + Sync + 'static {
+    let connections = options.connections();
+    let start = Instant::now();
+    let deadline = start + test_length;
+    let server = server.clone();
+    let options = options.clone();
+    let meter = options.progress.meter(phase);
+    let counter = meter.counter();
+    let network = options.network.clone();
+
+    let transferred = WorkerPool::new(connections).run((0..connections).collect(), move |_| {
+        let mut client = match OoklaClient::connect(&server, &options) {
+            Ok(c)   => c,
+            Err(e)  => {
+                warn!("Cannot connect to {} - {}", server.host, e);
+                return 0;
+            }
+        };
+        let mut total_bytes: u64 = 0;
+        while Instant::now() < deadline && !network.reached_max_bytes(counter.total()) {
+            match transfer(&mut client, deadline, &counter, network.max_bytes) {
+                Ok(s)   => {
+                    total_bytes = total_bytes + s;
+                    // Chunk was cut short by the deadline or max bytes, the connection is
+                    // out of sync with the server
+                    if s < CHUNK_SIZE {
+                        break;
+                    }
+                },
+                Err(e)  => {
+                    if logging::is_timeout(&e) {
+                        warn!("Timed out on {} after {} bytes", server.host, total_bytes);
+                    } else {
+                        warn!("Transfer with {} failed after {} bytes - {}", server.host, total_bytes, e);
+                    }
+                    break;
+                }
+            }
+            chatter_inline!(".");
+        }
+        debug!("Transferred {} bytes with {}", total_bytes, server.host);
+        total_bytes
+    });
+    meter.stop();
+    chatter_inline!("Done\n");
+
+    let elapsed = start.elapsed();
+    let elapsed_as_millis = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
+    (transferred.iter().sum(), elapsed_as_millis)
+}
+
+
+fn unexpected(command: &str, response: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Unexpected response to {} - '{}'", command, response))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{OoklaClient, Transport, CHUNK_SIZE, BUFFER_SIZE};
+    use client::ClientOptions;
+    use config::{TestServerConfig, UploadConfig};
+    use progress::ByteCounter;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Local stand-in for the Ookla TCP service, answers HI, PING, DOWNLOAD and UPLOAD
+    fn stand_in_server() -> TestServerConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s)   => { thread::spawn(move || serve(s)); },
+                    Err(_)  => break
+                }
+            }
+        });
+
+        TestServerConfig {
+            url: format!("http://{}/speedtest/upload.php", host),
+            latitude: 0.0,
+            longitude: 0.0,
+            name: "stand-in".to_string(),
+            country: String::new(),
+            country_code: String::new(),
+            id: 1,
+            url2: String::new(),
+            host: host
+        }
+    }
+
+    fn serve(stream: TcpStream) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let parts: Vec<&str> = line.trim().split(' ').collect();
+            let response = match parts[0] {
+                "HI"        => "HELLO 2.7 (2.7.4) 2019-01-01.0000.abcdef\n".to_string(),
+                "PING"      => "PONG 1546300800000\n".to_string(),
+                "DOWNLOAD"  => {
+                    let size: usize = parts[1].parse().unwrap();
+                    let mut data = b"DOWNLOAD ".to_vec();
+                    data.resize(size - 1, b'x');
+                    data.push(b'\n');
+                    writer.write_all(&data).ok();
+                    continue;
+                },
+                "UPLOAD"    => {
+                    let size: usize = parts[1].parse().unwrap();
+                    let mut data = vec![0; size - line.len()];
+                    if reader.read_exact(&mut data).is_err() {
+                        return;
+                    }
+                    format!("OK {} 1546300800000\n", size)
+                },
+                _           => return
+            };
+            writer.write_all(response.as_bytes()).ok();
+        }
+    }
+
+    fn far_deadline() -> Instant {
+        Instant::now() + Duration::from_secs(30)
+    }
+
+    #[test]
+    fn parse_transport_test() {
+        assert_eq!(Ok(Transport::Ookla), Transport::parse("ookla"));
+        assert_eq!(Ok(Transport::Http), Transport::parse("HTTP"));
+        assert!(Transport::parse("udp").is_err());
+    }
+
+    #[test]
+    fn ping_test() {
+        let server = stand_in_server();
+        let mut client = OoklaClient::connect(&server, &ClientOptions::default()).unwrap();
+        assert!(client.ping().unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn download_and_upload_test() {
+        let server = stand_in_server();
+        let mut client = OoklaClient::connect(&server, &ClientOptions::default()).unwrap();
+        let counter = ByteCounter::default();
+        assert_eq!(100000, client.download(100000, far_deadline(), &counter, None).unwrap());
+        assert_eq!(100000, client.upload(100000, far_deadline(), &counter, None).unwrap());
+        assert_eq!(200000, counter.total());
+        // Connection is still in sync after both transfers
+        assert!(client.ping().is_ok());
+        client.quit();
+    }
+
+    #[test]
+    fn download_stops_at_max_bytes_test() {
+        let server = stand_in_server();
+        let mut options = ClientOptions { connections: 4, ..ClientOptions::default() };
+        options.network.max_bytes = Some(3 * CHUNK_SIZE / 2);
+        let (total_bytes, _, _) = super::perform_download_test(&server, &options);
+        assert!(total_bytes > 0);
+        assert!(total_bytes <= 3 * CHUNK_SIZE / 2 + BUFFER_SIZE as u64);
+
+        let (total_bytes, _, _) = super::perform_upload_test(&server, &UploadConfig::default(), &options);
+        assert!(total_bytes > 0);
+        assert!(total_bytes <= 3 * CHUNK_SIZE / 2 + BUFFER_SIZE as u64);
+    }
+
+    #[test]
+    fn latency_of_unreachable_server_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = stand_in_server();
+        server.host = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        assert_eq!(None, super::measure_latency(&server, &ClientOptions::default()));
+    }
+}
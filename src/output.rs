@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RESULTS_ONLY: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+
+/// When stdout is read by scripts (--simple) or monitoring (Nagios thresholds) it only
+/// gets the results, progress messages ("chatter") go to stderr instead.
+pub fn set_results_only(results_only: bool) {
+    RESULTS_ONLY.store(results_only, Ordering::SeqCst);
+}
+
+
+pub fn is_results_only() -> bool {
+    RESULTS_ONLY.load(Ordering::SeqCst)
+}
+
+
+/// -q drops chatter altogether, results and errors are still shown
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::SeqCst);
+}
+
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::SeqCst)
+}
+
+
+/// Where a line goes, None when it is dropped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    Stdout,
+    Stderr
+}
+
+
+/// Results of a run (speeds, latency, comparisons) are shown with -q as well, only
+/// progress messages are dropped. Both go to stderr when stdout is for results only.
+pub fn destination(results_only: bool, quiet: bool, is_result: bool) -> Option<Destination> {
+    if quiet && !is_result {
+        None
+    } else if results_only {
+        Some(Destination::Stderr)
+    } else {
+        Some(Destination::Stdout)
+    }
+}
+
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! print_line {
+    ($is_result:expr, $($arg:tt)*) => {
+        match $crate::output::destination($crate::output::is_results_only(), $crate::output::is_quiet(), $is_result) {
+            Some($crate::output::Destination::Stdout)   => println!($($arg)*),
+            Some($crate::output::Destination::Stderr)   => eprintln!($($arg)*),
+            None                                        => {}
+        }
+    };
+}
+
+
+/// println! for progress messages, on stderr when stdout is for results only
+#[macro_export]
+macro_rules! chatter {
+    ($($arg:tt)*) => {
+        print_line!(false, $($arg)*)
+    };
+}
+
+
+/// println! for results of tests, like chatter but also shown with -q
+#[macro_export]
+macro_rules! result {
+    ($($arg:tt)*) => {
+        print_line!(true, $($arg)*)
+    };
+}
+
+
+/// print! for progress messages e.g. dots, flushed straight away
+#[macro_export]
+macro_rules! chatter_inline {
+    ($($arg:tt)*) => {{
+        use std::io::Write;
+        if !$crate::output::is_quiet() {
+            if $crate::output::is_results_only() {
+                eprint!($($arg)*);
+                ::std::io::stderr().flush().ok();
+            } else {
+                print!($($arg)*);
+                ::std::io::stdout().flush().ok();
+            }
+        }
+    }};
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Destination, destination};
+
+    #[test]
+    fn destination_test() {
+        assert_eq!(Some(Destination::Stdout), destination(false, false, false));
+        assert_eq!(Some(Destination::Stderr), destination(true, false, false));
+        assert_eq!(None, destination(false, true, false));
+        // -q still shows e.g. "Download speed: ..."
+        assert_eq!(Some(Destination::Stdout), destination(false, true, true));
+        assert_eq!(Some(Destination::Stderr), destination(true, true, true));
+    }
+}
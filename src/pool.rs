@@ -0,0 +1,151 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::client::Client;
+use hyper::client::response::Response;
+use hyper::header::Headers;
+
+use client::{ClientOptions, new_pooled_client, default_headers};
+use worker::WorkerPool;
+
+/// Persistent connections per test phase when not set in options
+pub const DEFAULT_CONNECTIONS: usize = 8;
+
+
+/// Keep-alive client shared by a fixed number of workers. Every worker sends its
+/// requests one after another, so after the first request the connection is taken
+/// from the pool instead of paying for a new TCP (and TLS) handshake.
+pub struct ConnectionPool {
+    client: Arc<Client>,
+    headers: Headers,
+    connections: usize
+}
+
+
+impl ConnectionPool {
+    pub fn new(options: &ClientOptions, url: &str, io_timeout: Duration) -> ConnectionPool {
+        let connections = options.connections();
+        ConnectionPool {
+            client: Arc::new(new_pooled_client(options, url, io_timeout, connections)),
+            headers: default_headers(options),
+            connections: connections
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn headers(&self) -> Headers {
+        self.headers.clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Runs `job` for every item on a worker pool with one worker per connection, each
+    /// worker sends its requests one after another so its connection is reused.
+    pub fn run<T, R, F>(&self, items: Vec<T>, job: F) -> Vec<R>
+        where T: Send + 'static,
+              R: Send + 'static,
+              F: Fn(&Client, &Headers, T) -> R + Send + Sync + 'static {
+        let client = self.client.clone();
+        // Headers are not Sync, every job gets its own copy
+        let headers = Mutex::new(self.headers.clone());
+        WorkerPool::new(self.connections).run(items, move |item| {
+            let job_headers = headers.lock().map(|h| h.clone()).unwrap_or(Headers::new());
+            job(&client, &job_headers, item)
+        })
+    }
+}
+
+
+/// Reads what is left of the response body, connection only goes back to the pool
+/// once the body has been read to the end.
+pub fn drain(response: &mut Response) -> io::Result<u64> {
+    io::copy(response, &mut io::sink())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionPool, drain};
+    use client::ClientOptions;
+    use hyper;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Minimal keep-alive HTTP server answering every request with "ok", returns its
+    /// url and the number of accepted connections.
+    fn keep_alive_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/speedtest/latency.txt", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s)   => s,
+                    Err(_)  => break
+                };
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut request: Vec<u8> = Vec::new();
+                    let mut buf = [0; 1024];
+                    loop {
+                        let size = match stream.read(&mut buf) {
+                            Ok(0) | Err(_)  => return,
+                            Ok(s)           => s
+                        };
+                        request.extend_from_slice(&buf[..size]);
+                        while let Some(end) = find_header_end(&request) {
+                            request.drain(..end);
+                            if stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (url, accepted)
+    }
+
+    fn find_header_end(request: &[u8]) -> Option<usize> {
+        request.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+    }
+
+    #[test]
+    fn connections_are_reused_test() {
+        let (url, accepted) = keep_alive_server();
+        let options = ClientOptions { connections: 2, ..ClientOptions::default() };
+        let pool = ConnectionPool::new(&options, &url, Duration::from_secs(5));
+
+        let urls: Vec<String> = (0..10).map(|_| url.clone()).collect();
+        let statuses = pool.run(urls, |client, headers, url| {
+            let mut response = client.get(url.as_str()).headers(headers.clone()).send().unwrap();
+            drain(&mut response).unwrap();
+            response.status
+        });
+
+        assert_eq!(10, statuses.len());
+        assert!(statuses.iter().all(|s| *s == hyper::Ok));
+        assert!(accepted.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn run_with_fewer_items_than_connections_test() {
+        let options = ClientOptions { connections: 4, ..ClientOptions::default() };
+        let pool = ConnectionPool::new(&options, "http://127.0.0.1/", Duration::from_secs(5));
+        let mut results = pool.run(vec![1, 2], |_, _, n| n * 10);
+        results.sort();
+        assert_eq!(vec![10, 20], results);
+    }
+}
@@ -0,0 +1,385 @@
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::{Json, ToJson};
+use websocket::OwnedMessage;
+use websocket::sync::Server;
+
+use config::TestServerConfig;
+use results::{TestResult, RunSummary};
+use utils::compute_speed_in_mbps;
+
+const SAMPLE_INTERVAL_MILLIS: u64 = 500;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Latency,
+    Download,
+    Upload
+}
+
+
+impl Phase {
+    pub fn label(&self) -> &'static str {
+        match *self {
+            Phase::Latency  => "latency",
+            Phase::Download => "download",
+            Phase::Upload   => "upload"
+        }
+    }
+}
+
+
+/// What happens during a run, in the order it happens
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    /// Server picked for the run and its latency
+    Server { server: TestServerConfig, latency_millis: Option<u64> },
+    /// Given test moves on to a phase
+    Phase { test_number: u64, phase: Phase },
+    /// Bytes transferred since the phase started, speed is over the last sample interval
+    Throughput { phase: Phase, total_bytes: u64, elapsed_millis: u64, speed_mbps: f64 },
+    /// Idle latency in latency phase, loaded latency probes in download and upload phases
+    Latency { phase: Phase, millis: u64 },
+    Result(TestResult),
+    Summary(RunSummary)
+}
+
+
+impl ToJson for ProgressEvent {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        let event = match *self {
+            ProgressEvent::Server { ref server, latency_millis } => {
+                obj.insert("id".to_string(), server.id.to_json());
+                obj.insert("name".to_string(), server.name.to_json());
+                obj.insert("country".to_string(), server.country.to_json());
+                obj.insert("host".to_string(), server.host.to_json());
+                obj.insert("latency_millis".to_string(), latency_millis.to_json());
+                "server"
+            },
+            ProgressEvent::Phase { test_number, phase }   => {
+                obj.insert("test_number".to_string(), test_number.to_json());
+                obj.insert("phase".to_string(), phase.label().to_json());
+                "phase"
+            },
+            ProgressEvent::Throughput { phase, total_bytes, elapsed_millis, speed_mbps } => {
+                obj.insert("phase".to_string(), phase.label().to_json());
+                obj.insert("total_bytes".to_string(), total_bytes.to_json());
+                obj.insert("elapsed_millis".to_string(), elapsed_millis.to_json());
+                obj.insert("speed_mbps".to_string(), speed_mbps.to_json());
+                "throughput"
+            },
+            ProgressEvent::Latency { phase, millis }      => {
+                obj.insert("phase".to_string(), phase.label().to_json());
+                obj.insert("millis".to_string(), millis.to_json());
+                "latency"
+            },
+            ProgressEvent::Result(ref result)               => {
+                obj.insert("result".to_string(), result.to_json());
+                "result"
+            },
+            ProgressEvent::Summary(ref summary)             => {
+                obj.insert("summary".to_string(), summary.to_json());
+                "summary"
+            }
+        };
+        obj.insert("event".to_string(), event.to_json());
+        Json::Object(obj)
+    }
+}
+
+
+/// Hands progress events to every subscriber. Clones share subscribers, so the copy in
+/// client options reaches all the test code. Emitting without subscribers is a no-op.
+#[derive(Clone, Default)]
+pub struct Progress {
+    subscribers: Arc<Mutex<Vec<Sender<ProgressEvent>>>>
+}
+
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let subscribers = self.subscribers.lock().map(|s| s.len()).unwrap_or(0);
+        write!(f, "Progress {{ subscribers: {} }}", subscribers)
+    }
+}
+
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress::default()
+    }
+
+    /// Events emitted from now on, the subscription ends when the receiver is dropped
+    pub fn subscribe(&self) -> Receiver<ProgressEvent> {
+        let (tx, rx) = channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|s| s.send(event.clone()).is_ok());
+        }
+    }
+
+    /// Starts sampling throughput of a download or upload phase
+    pub fn meter(&self, phase: Phase) -> ThroughputMeter {
+        ThroughputMeter::start(self.clone(), phase)
+    }
+}
+
+
+/// Bytes transferred so far, shared between the workers of a test phase. 64 bits even
+/// on 32 bit targets, where a usize would wrap after 4GiB.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounter {
+    bytes: Arc<AtomicU64>
+}
+
+
+impl ByteCounter {
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Adds up to `wanted` bytes without going over `max_bytes` and returns how many were
+    /// added, 0 once max_bytes is reached. Bytes not transferred after all are taken back
+    /// with `remove`.
+    pub fn reserve(&self, wanted: u64, max_bytes: Option<u64>) -> u64 {
+        let mut current = self.bytes.load(Ordering::SeqCst);
+        loop {
+            let allowed = match max_bytes {
+                Some(max)   => min(wanted, max.saturating_sub(current)),
+                None        => wanted
+            };
+            if allowed == 0 {
+                return 0;
+            }
+            match self.bytes.compare_exchange(current, current + allowed, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_)       => return allowed,
+                Err(actual) => current = actual
+            }
+        }
+    }
+
+    pub fn remove(&self, bytes: u64) {
+        self.bytes.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+
+/// Emits a throughput event every SAMPLE_INTERVAL_MILLIS from a side thread, and a
+/// last one when stopped.
+pub struct ThroughputMeter {
+    counter: ByteCounter,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>
+}
+
+
+impl ThroughputMeter {
+    fn start(progress: Progress, phase: Phase) -> ThroughputMeter {
+        let counter = ByteCounter::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let sampled = counter.clone();
+        let stop_flag = stop.clone();
+
+        let handle = thread::spawn(move || {
+            let start = Instant::now();
+            let mut last_bytes: u64 = 0;
+            let mut last_millis: u64 = 0;
+            loop {
+                // Unparked early when stopped
+                thread::park_timeout(Duration::from_millis(SAMPLE_INTERVAL_MILLIS));
+                let stopped = stop_flag.load(Ordering::SeqCst);
+
+                let elapsed = start.elapsed();
+                let elapsed_millis = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
+                let total_bytes = sampled.total();
+                let interval_millis = elapsed_millis - last_millis;
+                progress.emit(ProgressEvent::Throughput {
+                    phase: phase,
+                    total_bytes: total_bytes,
+                    elapsed_millis: elapsed_millis,
+                    speed_mbps: if interval_millis > 0 {
+                        compute_speed_in_mbps(total_bytes - last_bytes, interval_millis)
+                    } else {
+                        0.0
+                    }
+                });
+                last_bytes = total_bytes;
+                last_millis = elapsed_millis;
+
+                if stopped {
+                    break;
+                }
+            }
+        });
+
+        ThroughputMeter { counter: counter, stop: stop, handle: handle }
+    }
+
+    pub fn counter(&self) -> ByteCounter {
+        self.counter.clone()
+    }
+
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.thread().unpark();
+        self.handle.join().ok();
+    }
+}
+
+
+/// Serves every progress event as a JSON text message to all connected WebSocket
+/// clients, returns the address it listens on.
+pub fn serve(address: &str, progress: Progress) -> Result<SocketAddr, String> {
+    let server = Server::bind(address).map_err(|e| format!("Cannot listen on {} - {}", address, e))?;
+    let local_address = server.local_addr().map_err(|e| format!("Cannot listen on {} - {}", address, e))?;
+
+    thread::spawn(move || {
+        for request in server.filter_map(Result::ok) {
+            let events = progress.subscribe();
+            thread::spawn(move || {
+                let mut client = match request.accept() {
+                    Ok(c)   => c,
+                    Err(_)  => return
+                };
+                // Ends when the client goes away and sending fails
+                for event in events.iter() {
+                    let message = OwnedMessage::Text(event.to_json().to_string());
+                    if client.send_message(&message).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(local_address)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteCounter, Phase, Progress, ProgressEvent, serve};
+    use rustc_serialize::json::{Json, ToJson};
+    use std::thread;
+    use std::time::Duration;
+    use websocket::{ClientBuilder, OwnedMessage};
+
+    #[test]
+    fn byte_counter_beyond_4gib_test() {
+        let counter = ByteCounter::default();
+        counter.add(4 * 1024 * 1024 * 1024);
+        counter.add(1024);
+        assert_eq!(4 * 1024 * 1024 * 1024 + 1024, counter.total());
+    }
+
+    #[test]
+    fn reserve_test() {
+        let counter = ByteCounter::default();
+        assert_eq!(8192, counter.reserve(8192, None));
+        assert_eq!(1808, counter.reserve(8192, Some(10000)));
+        assert_eq!(0, counter.reserve(8192, Some(10000)));
+        counter.remove(1000);
+        assert_eq!(9000, counter.total());
+        assert_eq!(1000, counter.reserve(8192, Some(10000)));
+    }
+
+    #[test]
+    fn subscribers_get_every_event_test() {
+        let progress = Progress::new();
+        let first = progress.subscribe();
+        let second = progress.subscribe();
+        progress.emit(ProgressEvent::Phase { test_number: 1, phase: Phase::Download });
+
+        for events in vec![first, second] {
+            match events.recv().unwrap() {
+                ProgressEvent::Phase { test_number, phase } => {
+                    assert_eq!(1, test_number);
+                    assert_eq!(Phase::Download, phase);
+                },
+                e   => panic!("unexpected event {:?}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn dropped_subscriber_is_forgotten_test() {
+        let progress = Progress::new();
+        drop(progress.subscribe());
+        progress.emit(ProgressEvent::Latency { phase: Phase::Latency, millis: 20 });
+        assert_eq!("Progress { subscribers: 0 }", format!("{:?}", progress));
+    }
+
+    #[test]
+    fn meter_counts_bytes_test() {
+        let progress = Progress::new();
+        let events = progress.subscribe();
+        let meter = progress.meter(Phase::Upload);
+        meter.counter().add(1000);
+        meter.counter().add(500);
+        meter.stop();
+
+        let last = events.try_iter().last().unwrap();
+        match last {
+            ProgressEvent::Throughput { phase, total_bytes, .. } => {
+                assert_eq!(Phase::Upload, phase);
+                assert_eq!(1500, total_bytes);
+            },
+            e   => panic!("unexpected event {:?}", e)
+        }
+    }
+
+    #[test]
+    fn event_json_test() {
+        let json = ProgressEvent::Latency { phase: Phase::Download, millis: 42 }.to_json();
+        assert_eq!(Some("latency"), json.find("event").and_then(|e| e.as_string()));
+        assert_eq!(Some("download"), json.find("phase").and_then(|p| p.as_string()));
+        assert_eq!(Some(42), json.find("millis").and_then(|m| m.as_u64()));
+    }
+
+    #[test]
+    fn websocket_client_gets_events_test() {
+        let progress = Progress::new();
+        let address = serve("127.0.0.1:0", progress.clone()).unwrap();
+        let mut client = ClientBuilder::new(&format!("ws://{}", address)).unwrap()
+            .connect_insecure()
+            .unwrap();
+
+        // Client is subscribed once its handshake has been accepted
+        let sender = progress.clone();
+        thread::spawn(move || {
+            for _ in 0..50 {
+                sender.emit(ProgressEvent::Phase { test_number: 1, phase: Phase::Upload });
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        match client.recv_message().unwrap() {
+            OwnedMessage::Text(text)    => {
+                let json = Json::from_str(&text).unwrap();
+                assert_eq!(Some("phase"), json.find("event").and_then(|e| e.as_string()));
+                assert_eq!(Some("upload"), json.find("phase").and_then(|p| p.as_string()));
+            },
+            m                           => panic!("unexpected message {:?}", m)
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+
+use csv;
+use rustc_serialize::json::{Json, ToJson};
+
+use config::{ClientConfig, TestServerConfig};
+use stats::Summary;
+use units::SpeedFormat;
+use time_utils::get_current_utc_time_as_string;
+
+/// Column names of results in Mbps, speed columns are named after the unit of the output
+pub const CSV_COLUMN_NAMES: &'static str = "test_number,server_url,rx_start,rx_total_bytes,rx_total_millis,rx_speed_mbps,rx_end,tx_start,tx_total_bytes,tx_total_millis,tx_speed_mbps,tx_end,latency_millis,source,ip_version,rx_loaded_latency_millis,tx_loaded_latency_millis,bufferbloat_grade,http_latency_millis,tcp_latency_millis,rx_skipped,tx_skipped,ping_failed";
+
+
+/// Outcome of a single download/upload test against a server. This is what gets
+/// written to csv files and stored in the history database.
+#[derive(Debug, Clone, Default)]
+pub struct TestResult {
+    pub test_number: u64,
+    pub tested_at: String,
+    pub client_ip: String,
+    pub client_isp: String,
+    pub server_id: u64,
+    pub server_name: String,
+    pub server_country: String,
+    pub server_url: String,
+    pub latency_millis: u64,
+    pub rx_start: String,
+    pub rx_total_bytes: u64,
+    pub rx_total_millis: u64,
+    pub rx_speed_mbps: f64,
+    pub rx_end: String,
+    pub tx_start: String,
+    pub tx_total_bytes: u64,
+    pub tx_total_millis: u64,
+    pub tx_speed_mbps: f64,
+    pub tx_end: String,
+    /// Source address/interface connections were bound to, empty if not bound
+    pub source: String,
+    /// IPv4/IPv6 when connections were restricted to an address family
+    pub ip_version: String,
+    /// Median latency while downloading/uploading, 0 when not measured
+    pub rx_loaded_latency_millis: u64,
+    pub tx_loaded_latency_millis: u64,
+    pub bufferbloat_grade: String,
+    /// Latency measured with both ping methods, latency_millis is the one selected
+    pub http_latency_millis: u64,
+    pub tcp_latency_millis: u64,
+    /// Download/upload phase was not run, e.g. with --ping-only
+    pub rx_skipped: bool,
+    pub tx_skipped: bool,
+    /// Server did not answer any ping, latency_millis is 0 then
+    pub ping_failed: bool
+}
+
+
+impl TestResult {
+    pub fn new(test_number: u64, client: &ClientConfig, server: &TestServerConfig,
+               server_url: &str, latency_millis: Option<u64>) -> TestResult {
+        TestResult {
+            test_number: test_number,
+            tested_at: get_current_utc_time_as_string(),
+            client_ip: client.ip.clone(),
+            client_isp: client.isp.clone(),
+            server_id: server.id,
+            server_name: server.name.clone(),
+            server_country: server.country.clone(),
+            server_url: server_url.to_string(),
+            latency_millis: latency_millis.unwrap_or(0),
+            ping_failed: latency_millis.is_none(),
+            ..TestResult::default()
+        }
+    }
+
+    /// Row matching csv_column_names
+    pub fn to_csv_record(&self, format: &SpeedFormat) -> Vec<String> {
+        vec![
+            self.test_number.to_string(),
+            self.server_url.clone(),
+            self.rx_start.clone(),
+            self.rx_total_bytes.to_string(),
+            self.rx_total_millis.to_string(),
+            format.csv_value(self.rx_speed_mbps),
+            self.rx_end.clone(),
+            self.tx_start.clone(),
+            self.tx_total_bytes.to_string(),
+            self.tx_total_millis.to_string(),
+            format.csv_value(self.tx_speed_mbps),
+            self.tx_end.clone(),
+            self.latency_millis.to_string(),
+            self.source.clone(),
+            self.ip_version.clone(),
+            self.rx_loaded_latency_millis.to_string(),
+            self.tx_loaded_latency_millis.to_string(),
+            self.bufferbloat_grade.clone(),
+            self.http_latency_millis.to_string(),
+            self.tcp_latency_millis.to_string(),
+            self.rx_skipped.to_string(),
+            self.tx_skipped.to_string(),
+            self.ping_failed.to_string()
+        ]
+    }
+}
+
+
+impl TestResult {
+    /// Same as to_json with speeds in given format, keys are named after the unit
+    pub fn to_json_with(&self, format: &SpeedFormat) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("test_number".to_string(), self.test_number.to_json());
+        obj.insert("tested_at".to_string(), self.tested_at.to_json());
+        obj.insert("client_ip".to_string(), self.client_ip.to_json());
+        obj.insert("client_isp".to_string(), self.client_isp.to_json());
+        obj.insert("server_id".to_string(), self.server_id.to_json());
+        obj.insert("server_name".to_string(), self.server_name.to_json());
+        obj.insert("server_country".to_string(), self.server_country.to_json());
+        obj.insert("server_url".to_string(), self.server_url.to_json());
+        obj.insert("latency_millis".to_string(), self.latency_millis.to_json());
+        obj.insert("rx_start".to_string(), self.rx_start.to_json());
+        obj.insert("rx_total_bytes".to_string(), self.rx_total_bytes.to_json());
+        obj.insert("rx_total_millis".to_string(), self.rx_total_millis.to_json());
+        obj.insert(format.key("rx_speed"), format.convert(self.rx_speed_mbps).to_json());
+        obj.insert("rx_end".to_string(), self.rx_end.to_json());
+        obj.insert("tx_start".to_string(), self.tx_start.to_json());
+        obj.insert("tx_total_bytes".to_string(), self.tx_total_bytes.to_json());
+        obj.insert("tx_total_millis".to_string(), self.tx_total_millis.to_json());
+        obj.insert(format.key("tx_speed"), format.convert(self.tx_speed_mbps).to_json());
+        obj.insert("tx_end".to_string(), self.tx_end.to_json());
+        obj.insert("source".to_string(), self.source.to_json());
+        obj.insert("ip_version".to_string(), self.ip_version.to_json());
+        obj.insert("rx_loaded_latency_millis".to_string(), self.rx_loaded_latency_millis.to_json());
+        obj.insert("tx_loaded_latency_millis".to_string(), self.tx_loaded_latency_millis.to_json());
+        obj.insert("bufferbloat_grade".to_string(), self.bufferbloat_grade.to_json());
+        obj.insert("http_latency_millis".to_string(), self.http_latency_millis.to_json());
+        obj.insert("tcp_latency_millis".to_string(), self.tcp_latency_millis.to_json());
+        obj.insert("rx_skipped".to_string(), self.rx_skipped.to_json());
+        obj.insert("tx_skipped".to_string(), self.tx_skipped.to_json());
+        obj.insert("ping_failed".to_string(), self.ping_failed.to_json());
+        Json::Object(obj)
+    }
+}
+
+
+impl ToJson for TestResult {
+    fn to_json(&self) -> Json {
+        self.to_json_with(&SpeedFormat::default())
+    }
+}
+
+
+/// Statistics over all the tests of a run
+#[derive(Debug, Default, Clone)]
+pub struct RunSummary {
+    pub num_tests: u64,
+    /// Tests that ran the download/upload phase
+    pub num_downloads: u64,
+    pub num_uploads: u64,
+    /// Tests whose server answered the ping
+    pub num_pings: u64,
+    pub latency_millis: Summary,
+    pub rx_speed_mbps: Summary,
+    pub tx_speed_mbps: Summary
+}
+
+
+impl RunSummary {
+    /// Speeds are summarised over the tests that ran the phase only, latency over the
+    /// tests whose ping was answered
+    pub fn from_results(results: &[TestResult]) -> RunSummary {
+        let latencies: Vec<f64> = results.iter().filter(|r| !r.ping_failed).map(|r| r.latency_millis as f64).collect();
+        let rx_speeds: Vec<f64> = results.iter().filter(|r| !r.rx_skipped).map(|r| r.rx_speed_mbps).collect();
+        let tx_speeds: Vec<f64> = results.iter().filter(|r| !r.tx_skipped).map(|r| r.tx_speed_mbps).collect();
+
+        RunSummary {
+            num_tests: results.len() as u64,
+            num_downloads: rx_speeds.len() as u64,
+            num_uploads: tx_speeds.len() as u64,
+            num_pings: latencies.len() as u64,
+            latency_millis: Summary::from_values(&latencies),
+            rx_speed_mbps: Summary::from_values(&rx_speeds),
+            tx_speed_mbps: Summary::from_values(&tx_speeds)
+        }
+    }
+
+    /// One row per statistic, test_number column holds the name of the statistic and
+    /// only the speed and latency columns are filled in.
+    pub fn to_csv_records(&self, format: &SpeedFormat) -> Vec<Vec<String>> {
+        let rx = &self.rx_speed_mbps;
+        let tx = &self.tx_speed_mbps;
+        let latency = &self.latency_millis;
+        let rows = vec![
+            ("min", rx.min, tx.min, latency.min),
+            ("max", rx.max, tx.max, latency.max),
+            ("mean", rx.mean, tx.mean, latency.mean),
+            ("median", rx.median, tx.median, latency.median),
+            ("p90", rx.p90, tx.p90, latency.p90),
+            ("stddev", rx.stddev, tx.stddev, latency.stddev)
+        ];
+
+        rows.into_iter().map(|(name, rx_value, tx_value, latency_value)| {
+            let empty = String::new();
+            vec![name.to_string(), empty.clone(), empty.clone(), empty.clone(), empty.clone(),
+                 format.csv_value(rx_value), empty.clone(), empty.clone(), empty.clone(), empty.clone(),
+                 format.csv_value(tx_value), empty.clone(), latency_value.to_string(), empty.clone(), empty.clone(),
+                 empty.clone(), empty.clone(), empty.clone(), empty.clone(), empty.clone(),
+                 empty.clone(), empty.clone(), empty]
+        }).collect()
+    }
+}
+
+
+impl RunSummary {
+    pub fn to_json_with(&self, format: &SpeedFormat) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("num_tests".to_string(), self.num_tests.to_json());
+        obj.insert("num_downloads".to_string(), self.num_downloads.to_json());
+        obj.insert("num_uploads".to_string(), self.num_uploads.to_json());
+        obj.insert("num_pings".to_string(), self.num_pings.to_json());
+        obj.insert("latency_millis".to_string(), self.latency_millis.to_json());
+        obj.insert(format.key("rx_speed"), format.convert_summary(&self.rx_speed_mbps).to_json());
+        obj.insert(format.key("tx_speed"), format.convert_summary(&self.tx_speed_mbps).to_json());
+        Json::Object(obj)
+    }
+}
+
+
+impl ToJson for RunSummary {
+    fn to_json(&self) -> Json {
+        self.to_json_with(&SpeedFormat::default())
+    }
+}
+
+
+/// CSV_COLUMN_NAMES with speed columns named after the unit of given format
+pub fn csv_column_names(format: &SpeedFormat) -> Vec<String> {
+    CSV_COLUMN_NAMES.split(',').map(|c| match c {
+        "rx_speed_mbps" => format.key("rx_speed"),
+        "tx_speed_mbps" => format.key("tx_speed"),
+        _               => c.to_string()
+    }).collect()
+}
+
+
+pub fn to_csv_string(results: &[TestResult], summary: &RunSummary, format: &SpeedFormat) -> String {
+    let mut writer = csv::Writer::from_memory();
+    writer.encode(csv_column_names(format)).ok();
+    for result in results {
+        writer.encode(result.to_csv_record(format)).ok();
+    }
+    for record in summary.to_csv_records(format) {
+        writer.encode(record).ok();
+    }
+    writer.into_string()
+}
+
+
+/// Tests and run summary as a JSON document - {"tests": [...], "summary": {...}}
+pub fn to_json_string(results: &[TestResult], summary: &RunSummary, format: &SpeedFormat) -> String {
+    let mut obj = BTreeMap::new();
+    obj.insert("tests".to_string(), Json::Array(results.iter().map(|r| r.to_json_with(format)).collect()));
+    obj.insert("summary".to_string(), summary.to_json_with(format));
+    format!("{}", Json::Object(obj).pretty())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{TestResult, RunSummary, CSV_COLUMN_NAMES, csv_column_names};
+    use units::{SpeedFormat, SpeedUnit};
+
+    fn test_result(rx: f64, tx: f64, latency: u64) -> TestResult {
+        TestResult {
+            rx_speed_mbps: rx,
+            tx_speed_mbps: tx,
+            latency_millis: latency,
+            ..TestResult::default()
+        }
+    }
+
+    #[test]
+    fn run_summary_test() {
+        let results = vec![test_result(10.0, 1.0, 30), test_result(30.0, 3.0, 10), test_result(20.0, 2.0, 20)];
+        let summary = RunSummary::from_results(&results);
+        assert_eq!(3, summary.num_tests);
+        assert_eq!(20.0, summary.rx_speed_mbps.median);
+        assert_eq!(3.0, summary.tx_speed_mbps.max);
+        assert_eq!(10.0, summary.latency_millis.min);
+    }
+
+    #[test]
+    fn run_summary_without_skipped_phases_test() {
+        let ping_only = TestResult { rx_skipped: true, tx_skipped: true, ..test_result(0.0, 0.0, 50) };
+        let results = vec![test_result(10.0, 1.0, 30), ping_only];
+        let summary = RunSummary::from_results(&results);
+        assert_eq!(2, summary.num_tests);
+        assert_eq!(1, summary.num_downloads);
+        assert_eq!(1, summary.num_uploads);
+        assert_eq!(10.0, summary.rx_speed_mbps.mean);
+        assert_eq!(40.0, summary.latency_millis.mean);
+    }
+
+    #[test]
+    fn run_summary_without_failed_pings_test() {
+        let unanswered = TestResult { ping_failed: true, ..test_result(20.0, 2.0, 0) };
+        let results = vec![test_result(10.0, 1.0, 30), unanswered];
+        let summary = RunSummary::from_results(&results);
+        assert_eq!(2, summary.num_downloads);
+        assert_eq!(1, summary.num_pings);
+        assert_eq!(30.0, summary.latency_millis.mean);
+        assert_eq!(15.0, summary.rx_speed_mbps.mean);
+    }
+
+    #[test]
+    fn csv_records_match_columns_test() {
+        let num_columns = CSV_COLUMN_NAMES.split(',').count();
+        let result = test_result(10.0, 1.0, 30);
+        assert_eq!(num_columns, result.to_csv_record(&SpeedFormat::default()).len());
+
+        let summary = RunSummary::from_results(&vec![result]);
+        for record in summary.to_csv_records(&SpeedFormat::default()) {
+            assert_eq!(num_columns, record.len());
+        }
+    }
+
+    #[test]
+    fn speeds_in_given_unit_test() {
+        let format = SpeedFormat { unit: SpeedUnit::MBps, precision: Some(1) };
+        let columns = csv_column_names(&format);
+        assert_eq!("rx_speed_mb_s", columns[5]);
+        assert_eq!("tx_speed_mb_s", columns[10]);
+
+        let result = TestResult { rx_total_bytes: 1000, ..test_result(100.0, 12.0, 30) };
+        let record = result.to_csv_record(&format);
+        assert_eq!("1000", record[3]);
+        assert_eq!("12.5", record[5]);
+        assert_eq!("1.5", record[10]);
+
+        let json = result.to_json_with(&format);
+        assert_eq!(Some(12.5), json.find("rx_speed_mb_s").and_then(|s| s.as_f64()));
+        assert!(json.find("rx_speed_mbps").is_none());
+    }
+}
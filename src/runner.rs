@@ -0,0 +1,695 @@
+use std::cmp::{max, min};
+
+use rand::{thread_rng, Rng};
+
+use client::{ClientOptions, PingMethod};
+use config;
+use config::{FullConfig, TestServerConfig, find_ignore_ids};
+use connector::IpVersion;
+use history::HistoryStore;
+use ookla;
+use ookla::Transport;
+use bufferbloat::{LatencyProbe, LoadedLatency};
+use budget::DataBudget;
+use progress::{Phase, ProgressEvent};
+use results::{TestResult, RunSummary};
+use units::SpeedFormat;
+use time_utils::{get_current_time_as_string, get_current_utc_time_as_string};
+use {find_best_server_by_ping, rank_servers_by_ping, measure_latency, measure_http_latency,
+     measure_tcp_latency, perform_download_test, perform_upload_test, pick_n_closest_servers, parse_url,
+     describe_latency, CLOSEST_SERVERS};
+
+
+/// Everything a single run needs apart from where results are written, shared by the
+/// command line and daemon mode.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub number_of_tests: u64,
+    pub server_country: Option<String>,
+    pub server_country_code: Option<String>,
+    pub use_cached_servers: bool,
+    /// Speedtest config file used instead of fetching it
+    pub speedtest_config: Option<String>,
+    pub dual_stack: bool,
+    pub loaded_latency: bool,
+    /// Run download/upload phase of every test, latency is always measured
+    pub download: bool,
+    pub upload: bool,
+    /// Run tests against this many of the best servers instead of only the best one
+    pub compare: usize,
+    /// Run tests against these servers instead of the best one
+    pub compare_ids: Vec<u64>,
+    /// How the server of every test is picked when not comparing servers
+    pub server_strategy: ServerStrategy,
+    /// Number of best servers the strategy picks from
+    pub top_k: usize,
+    /// Monthly data allowance, runs are skipped once it is used up
+    pub budget: Option<DataBudget>,
+    /// Unit and precision speeds are printed in
+    pub speed_format: SpeedFormat,
+    pub client: ClientOptions
+}
+
+
+impl Default for RunOptions {
+    fn default() -> RunOptions {
+        RunOptions {
+            number_of_tests: 1,
+            server_country: None,
+            server_country_code: None,
+            use_cached_servers: false,
+            speedtest_config: None,
+            dual_stack: false,
+            loaded_latency: true,
+            download: true,
+            upload: true,
+            compare: 1,
+            compare_ids: Vec::new(),
+            server_strategy: ServerStrategy::Fixed,
+            top_k: CLOSEST_SERVERS,
+            budget: None,
+            speed_format: SpeedFormat::default(),
+            client: ClientOptions::default()
+        }
+    }
+}
+
+
+/// How the server of every test of a run is picked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerStrategy {
+    /// Server with lowest latency for every test
+    Fixed,
+    /// Best servers take turns
+    RoundRobin,
+    /// Any of the best servers
+    Random,
+    /// Best servers are pinged again before every test and the fastest one is used
+    RePingEach
+}
+
+
+impl ServerStrategy {
+    pub fn parse(strategy: &str) -> Result<ServerStrategy, String> {
+        match strategy.to_lowercase().as_str() {
+            "fixed"         => Ok(ServerStrategy::Fixed),
+            "round-robin"   => Ok(ServerStrategy::RoundRobin),
+            "random"        => Ok(ServerStrategy::Random),
+            "re-ping-each"  => Ok(ServerStrategy::RePingEach),
+            _               => Err(format!("Invalid server strategy '{}', it should be fixed, round-robin, random or re-ping-each", strategy))
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match *self {
+            ServerStrategy::Fixed       => "fixed",
+            ServerStrategy::RoundRobin  => "round-robin",
+            ServerStrategy::Random      => "random",
+            ServerStrategy::RePingEach  => "re-ping-each"
+        }
+    }
+}
+
+
+impl Default for ServerStrategy {
+    fn default() -> ServerStrategy {
+        ServerStrategy::Fixed
+    }
+}
+
+
+/// Hands out the server of every test of a run from servers ranked by latency
+struct ServerPicker<'a> {
+    strategy: ServerStrategy,
+    servers: Vec<(&'a TestServerConfig, Option<u64>)>,
+    used: Vec<u64>,
+    options: &'a ClientOptions
+}
+
+
+impl<'a> ServerPicker<'a> {
+    fn new(strategy: ServerStrategy, servers: Vec<(&'a TestServerConfig, Option<u64>)>, options: &'a ClientOptions) -> ServerPicker<'a> {
+        ServerPicker { strategy: strategy, servers: servers, used: Vec::new(), options: options }
+    }
+
+    /// Server of test `index` with its latency measured while picking, and whether that
+    /// latency is fresh (not used by an earlier test yet)
+    fn pick(&mut self, index: usize) -> (&'a TestServerConfig, Option<u64>, bool) {
+        let (server, latency) = match self.strategy {
+            ServerStrategy::Fixed       => self.servers[0],
+            ServerStrategy::RoundRobin  => self.servers[index % self.servers.len()],
+            ServerStrategy::Random      => self.servers[thread_rng().gen_range(0, self.servers.len())],
+            ServerStrategy::RePingEach  => {
+                if index > 0 {
+                    let options = self.options;
+                    let mut pinged: Vec<(&'a TestServerConfig, Option<u64>)> = self.servers.iter()
+                        .map(|&(s, _)| (s, measure_latency(s, options)))
+                        .collect();
+                    pinged.sort_by_key(|&(_, l)| l.unwrap_or(u64::max_value()));
+                    self.servers = pinged;
+                    // Every test uses a fresh latency
+                    self.used.clear();
+                }
+                self.servers[0]
+            }
+        };
+
+        if self.used.contains(&server.id) {
+            (server, latency, false)
+        } else {
+            self.used.push(server.id);
+            (server, latency, true)
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    pub started_at: String,
+    pub results: Vec<TestResult>,
+    pub summary: RunSummary
+}
+
+
+/// Picks the best server (or the servers to compare) and runs all tests against it,
+/// every result is recorded in `history` as soon as it is known.
+pub fn run(options: &RunOptions, history: Option<&HistoryStore>) -> Result<RunOutcome, String> {
+    let client_options = &options.client;
+    if budget_used_up(options)? {
+        return Err(format!("Monthly data budget of {} bytes is used up, skipping run",
+                           options.budget.as_ref().map(|b| b.limit_bytes).unwrap_or(0)));
+    }
+    let config = load_speedtest_config(options.speedtest_config.as_ref().map(|f| f.as_str()), client_options)?;
+
+    let test_servers: Vec<TestServerConfig> = config::get_all_test_servers(options.use_cached_servers, client_options);
+    chatter!("Total servers available: {:?}", test_servers.len());
+
+    let candidate_servers = if options.compare_ids.is_empty() {
+        candidate_servers(&config, test_servers, options)
+    } else {
+        servers_by_id(&test_servers, &options.compare_ids)
+    };
+
+    chatter!("Your address {:?} and ISP {:?}", config.client.ip, config.client.isp);
+    if !client_options.source().is_empty() {
+        chatter!("Running tests from {}", client_options.source());
+    }
+    if candidate_servers.is_empty() {
+        return Err("Cannot find any servers, please note that if you're searching by country name currently it is an exact match.".to_string());
+    }
+
+    // look for ping latency for all servers (or closest servers)
+    let comparing = !options.compare_ids.is_empty() || options.compare > 1;
+    let servers: Vec<(&TestServerConfig, Option<u64>)> = if comparing {
+        let ranked = rank_servers_by_ping(&candidate_servers, client_options);
+        print_ranked_servers(&ranked, client_options);
+        let count = if options.compare_ids.is_empty() { options.compare } else { ranked.len() };
+        if count > ranked.len() {
+            chatter!("Only {} servers available to compare", ranked.len());
+        }
+        ranked.into_iter().take(count).collect()
+    } else if options.server_strategy == ServerStrategy::Fixed {
+        vec![find_best_server_by_ping(&candidate_servers, client_options)]
+    } else {
+        let ranked = rank_servers_by_ping(&candidate_servers, client_options);
+        print_ranked_servers(&ranked, client_options);
+        chatter!("Picking the server of every test {} from the best {}", options.server_strategy.label(),
+                 min(options.top_k, ranked.len()));
+        ranked.into_iter().take(max(options.top_k, 1)).collect()
+    };
+
+    let run_started_at = get_current_utc_time_as_string();
+    let mut results: Vec<TestResult> = Vec::new();
+
+    let sizes: Vec<u64> = vec![32768, 65536, 131072, 262144, 524288, 1048576, 7340032];
+    let dimensions: Vec<u64> = vec![350, 500, 750, 1000, 1500, 2000, 2500, 3000];
+
+    // Dual stack runs every test over IPv4 and then IPv6 against the same server
+    let test_options: Vec<ClientOptions> = if options.dual_stack {
+        vec![client_options.with_ip_version(IpVersion::V4), client_options.with_ip_version(IpVersion::V6)]
+    } else {
+        vec![client_options.clone()]
+    };
+
+    // Compared servers get all tests one after another, otherwise the strategy picks
+    // the server of every test
+    let pickers: Vec<ServerPicker> = if comparing {
+        servers.iter().map(|s| ServerPicker::new(ServerStrategy::Fixed, vec![*s], client_options)).collect()
+    } else {
+        vec![ServerPicker::new(options.server_strategy, servers.clone(), client_options)]
+    };
+
+    'tests: for mut picker in pickers {
+        let mut last_server_id = None;
+        for i in 0..options.number_of_tests {
+            let current_test = i + 1;
+            let (server, picked_latency, fresh_latency) = picker.pick(i as usize);
+            if last_server_id != Some(server.id) {
+                if comparing && i == 0 {
+                    chatter!("");
+                    chatter!("Testing against {} ({}) id {}", server.name, server.country, server.id);
+                }
+                client_options.progress.emit(ProgressEvent::Server {
+                    server: server.clone(),
+                    latency_millis: picked_latency
+                });
+                last_server_id = Some(server.id);
+            }
+
+            for test_option in &test_options {
+                if budget_used_up(options)? {
+                    chatter!("Monthly data budget is used up, skipping remaining tests");
+                    break 'tests;
+                }
+                let test_option = &capped_by_budget(test_option, options.budget.as_ref())?;
+                if options.dual_stack {
+                    chatter!("Performing test {} over {}", current_test, test_option.ip_version.label());
+                } else if picker.strategy != ServerStrategy::Fixed {
+                    chatter!("Performing test {} against {} ({}) id {}", current_test, server.name, server.country, server.id);
+                } else {
+                    chatter!("Performing test {}", current_test);
+                }
+                test_option.progress.emit(ProgressEvent::Phase { test_number: current_test, phase: Phase::Latency });
+                // Latency measured while picking the server is reused
+                let test_latency = if fresh_latency && !options.dual_stack {
+                    picked_latency
+                } else {
+                    measure_latency(server, test_option)
+                };
+                if let Some(millis) = test_latency {
+                    test_option.progress.emit(ProgressEvent::Latency { phase: Phase::Latency, millis: millis });
+                }
+                let result = perform_test(current_test, &config, server, test_latency,
+                                          &dimensions, &sizes, test_option, options);
+                info!("Test {} against server {}: latency {}ms, downloaded {} bytes in {}ms, uploaded {} bytes in {}ms",
+                      current_test, server.id, result.latency_millis, result.rx_total_bytes, result.rx_total_millis,
+                      result.tx_total_bytes, result.tx_total_millis);
+
+                if let Some(store) = history {
+                    match store.record(&run_started_at, &result) {
+                        Ok(_)   => {},
+                        Err(e)  => warn!("Failed to store result in history database - {}", e)
+                    }
+                }
+                if let Some(ref budget) = options.budget {
+                    if let Err(e) = budget.record(result.rx_total_bytes + result.tx_total_bytes) {
+                        warn!("{}", e);
+                    }
+                }
+                results.push(result);
+            }
+        }
+    }
+
+    if options.dual_stack {
+        print_dual_stack_comparison(&results, &options.speed_format);
+    }
+    if comparing && servers.len() > 1 {
+        print_server_comparison(&results, &options.speed_format);
+    }
+
+    let summary = RunSummary::from_results(&results);
+    client_options.progress.emit(ProgressEvent::Summary(summary.clone()));
+    Ok(RunOutcome { started_at: run_started_at, results: results, summary: summary })
+}
+
+
+/// Servers of speedtest config's ignore list are left out, then only servers of given
+/// country are kept or else the closest ones.
+fn candidate_servers(config: &FullConfig, mut test_servers: Vec<TestServerConfig>,
+                     options: &RunOptions) -> Vec<TestServerConfig> {
+    let server_hint_config = &config.server;
+
+    // Use find_ignore_ids from config mod
+    let ignore_ids = find_ignore_ids(server_hint_config.ignoreids.clone());
+
+    // ignore servers on ignore list
+    // TODO: Pass in argument to switch off ignore servers recommended by speedtest config?
+    test_servers.retain(|ref mut server| {
+        // If not ignore ids list keep this server
+        !ignore_ids.contains(&server.id)
+    });
+
+    chatter!("Total servers available after ignoring: {:?}", test_servers.len());
+    chatter!("");
+
+    match options.server_country {
+        Some(ref sc)    => {
+            test_servers.retain(|ref mut server| {
+                // If not ignore ids list keep this server
+                server.country.to_lowercase() == sc.to_lowercase()
+            });
+
+            if test_servers.len() > 10 {
+                chatter!("Number of servers in {} are {} - it might take a while to find best server", sc, test_servers.len());
+            } else {
+                chatter!("Number of servers in {} are {}", sc, test_servers.len());
+            }
+
+            test_servers
+        },
+        None            => {
+            match options.server_country_code {
+                Some(ref scc) => {
+                    test_servers.retain(|ref mut server| {
+                        // If not ignore ids list keep this server
+                        server.country_code.to_lowercase() == scc.to_lowercase()
+                    });
+
+                    if test_servers.len() > 10 {
+                        chatter!("Number of servers in {} are {} - it might take a while to find best server", scc, test_servers.len());
+                    } else {
+                        chatter!("Number of servers in {} are {}", scc, test_servers.len());
+                    }
+
+                    test_servers
+                },
+                None => {
+                    // both server country and server country code are not set.
+                    // look for closest servers - we should add a switch to avoid this distance check
+                    let client_conf = &config.client;
+                    let client_location = (client_conf.lat, client_conf.lon);
+                    let mut closest_servers: Vec<TestServerConfig> = Vec::new();
+                    let wanted = max(CLOSEST_SERVERS, max(options.compare, options.top_k));
+                    pick_n_closest_servers(client_location, &test_servers, wanted, &mut closest_servers);
+                    closest_servers
+                }
+            }
+        }
+    }
+}
+
+
+/// Servers with given ids in the given order, the ignore list does not apply to them
+pub fn servers_by_id(test_servers: &[TestServerConfig], ids: &[u64]) -> Vec<TestServerConfig> {
+    let mut servers = Vec::new();
+    for id in ids {
+        match test_servers.iter().find(|s| s.id == *id) {
+            Some(s) => servers.push(s.clone()),
+            None    => chatter!("Cannot find server with id {}", id)
+        }
+    }
+    servers
+}
+
+
+/// Config from given file, otherwise fetched from speedtest.net falling back to the
+/// built-in one when that fails.
+fn load_speedtest_config(file_name: Option<&str>, options: &ClientOptions) -> Result<FullConfig, String> {
+    if let Some(f) = file_name {
+        return FullConfig::from_file(f);
+    }
+
+    let config = FullConfig::fetch(options);
+    if config.parsing_succeeded {
+        Ok(config)
+    } else {
+        warn!("Cannot fetch speedtest config, using built-in defaults. Your address, ISP and location are unknown so use --server-country or --server-country-code to pick servers");
+        Ok(FullConfig::default_config())
+    }
+}
+
+
+/// Latency-only runs hardly use any data so they go ahead anyway
+fn budget_used_up(options: &RunOptions) -> Result<bool, String> {
+    match options.budget {
+        Some(ref budget) if options.download || options.upload => budget.remaining().map(|r| r == 0),
+        _                                                       => Ok(false)
+    }
+}
+
+
+/// Options of a test that may only use what is left of the budget
+fn capped_by_budget(options: &ClientOptions, budget: Option<&DataBudget>) -> Result<ClientOptions, String> {
+    let mut capped = options.clone();
+    if let Some(b) = budget {
+        let remaining = b.remaining()?;
+        capped.network.max_bytes = Some(options.network.max_bytes.map_or(remaining, |m| min(m, remaining)));
+    }
+    Ok(capped)
+}
+
+
+fn perform_test(current_test: u64, config: &FullConfig, server: &TestServerConfig,
+                latency: Option<u64>, dimensions: &Vec<u64>, sizes: &Vec<u64>,
+                options: &ClientOptions, run_options: &RunOptions) -> TestResult {
+    let server_url = parse_url(&server.url);
+    let mut result = TestResult::new(current_test, &config.client, server, &server_url, latency);
+    // Both ping methods are reported to tell network RTT and server responsiveness apart
+    // (servers only speaking the Ookla protocol have no HTTP latency), failed ones are 0
+    let (http_latency, tcp_latency) = match (options.transport, options.ping_method) {
+        (Transport::Http, PingMethod::Http) => (latency, measure_tcp_latency(server, options)),
+        (Transport::Http, PingMethod::Tcp)  => (measure_http_latency(server, options), latency),
+        (Transport::Ookla, _)               => (None, measure_tcp_latency(server, options))
+    };
+    result.http_latency_millis = http_latency.unwrap_or(0);
+    result.tcp_latency_millis = tcp_latency.unwrap_or(0);
+    if options.transport == Transport::Ookla {
+        result!("Latency: {} (TCP connect {})", describe_latency(latency), describe_latency(tcp_latency));
+    } else {
+        result!("Latency: {} (HTTP {}, TCP connect {})", describe_latency(latency),
+                describe_latency(http_latency), describe_latency(tcp_latency));
+    }
+    result.source = options.source();
+    result.ip_version = options.ip_version.label().to_string();
+
+    // Start tests against chosen server - these download/upload tests will
+    // run in separate threads
+    // Idle latency is measured the same way as the probes under load, latency.txt fetches
+    // over HTTP (whatever the ping method) or PING of the Ookla protocol
+    let idle_millis = match options.transport {
+        Transport::Http     => http_latency,
+        Transport::Ookla    => latency
+    };
+    let mut latency_under_load = LoadedLatency { idle_millis: idle_millis, ..LoadedLatency::default() };
+
+    let loaded_latency = run_options.loaded_latency;
+    if run_options.download {
+        chatter_inline!("Running download tests...");
+        result.rx_start = get_current_time_as_string();
+        options.progress.emit(ProgressEvent::Phase { test_number: current_test, phase: Phase::Download });
+        let probe = if loaded_latency { Some(LatencyProbe::start(server, options, Phase::Download)) } else { None };
+        let (rx_total_bytes, rx_total_millis, rx_speed_in_mbps) = match options.transport {
+            Transport::Http     => perform_download_test(&server_url, dimensions, options),
+            Transport::Ookla    => ookla::perform_download_test(server, options)
+        };
+        latency_under_load.download_millis = probe.and_then(|p| p.stop());
+        result.rx_total_bytes = rx_total_bytes;
+        result.rx_total_millis = rx_total_millis;
+        result.rx_speed_mbps = rx_speed_in_mbps;
+        result.rx_end = get_current_time_as_string();
+        result!("Download speed: {}", run_options.speed_format.format(rx_speed_in_mbps));
+        chatter!("");
+    } else {
+        result.rx_skipped = true;
+        chatter!("Download test skipped");
+    }
+
+    // Byte limit is for the whole test so upload gets what download left
+    let mut upload_options = options.clone();
+    upload_options.network.max_bytes = options.network.max_bytes.map(|m| m.saturating_sub(result.rx_total_bytes));
+    if run_options.upload && upload_options.network.reached_max_bytes(0) {
+        result.tx_skipped = true;
+        chatter!("Upload test skipped, download used up the byte limit");
+    } else if run_options.upload {
+        let options = &upload_options;
+        chatter_inline!("Running upload tests...");
+        result.tx_start = get_current_time_as_string();
+        options.progress.emit(ProgressEvent::Phase { test_number: current_test, phase: Phase::Upload });
+        let probe = if loaded_latency { Some(LatencyProbe::start(server, options, Phase::Upload)) } else { None };
+        let (tx_total_bytes, tx_total_millis, tx_speed_in_mbps) = match options.transport {
+            Transport::Http     => perform_upload_test(&server.url, &config.upload, sizes, options),
+            Transport::Ookla    => ookla::perform_upload_test(server, &config.upload, options)
+        };
+        latency_under_load.upload_millis = probe.and_then(|p| p.stop());
+        result.tx_total_bytes = tx_total_bytes;
+        result.tx_total_millis = tx_total_millis;
+        result.tx_speed_mbps = tx_speed_in_mbps;
+        result.tx_end = get_current_time_as_string();
+        result!("Upload speed: {}", run_options.speed_format.format(tx_speed_in_mbps));
+        chatter!("Done");
+    } else {
+        result.tx_skipped = true;
+        chatter!("Upload test skipped");
+    }
+
+    // Nothing to grade when only latency was measured
+    if loaded_latency && (run_options.download || run_options.upload) {
+        result.rx_loaded_latency_millis = latency_under_load.download_millis.unwrap_or(0);
+        result.tx_loaded_latency_millis = latency_under_load.upload_millis.unwrap_or(0);
+        result.bufferbloat_grade = latency_under_load.grade().map(|g| g.label().to_string()).unwrap_or(String::new());
+        print_loaded_latency(&latency_under_load);
+    }
+    options.progress.emit(ProgressEvent::Result(result.clone()));
+    result
+}
+
+
+fn print_loaded_latency(latency: &LoadedLatency) {
+    let describe = |loaded: Option<u64>, increase: Option<u64>| -> String {
+        match (loaded, increase) {
+            (Some(l), Some(i))  => format!("{}ms (+{}ms)", l, i),
+            _                   => "n/a".to_string()
+        }
+    };
+    result!("Loaded latency: download {}, upload {}",
+            describe(latency.download_millis, latency.download_increase()),
+            describe(latency.upload_millis, latency.upload_increase()));
+    match latency.grade() {
+        Some(g) => result!("Bufferbloat grade: {}", g.label()),
+        None    => result!("Bufferbloat grade: n/a")
+    }
+}
+
+
+/// IPv4 and IPv6 results of a dual stack run next to each other, mean over all tests
+/// of each family.
+fn print_dual_stack_comparison(results: &Vec<TestResult>, format: &SpeedFormat) {
+    let by_version = |version: IpVersion| -> RunSummary {
+        let version_results: Vec<TestResult> = results.iter()
+            .filter(|r| r.ip_version == version.label())
+            .cloned()
+            .collect();
+        RunSummary::from_results(&version_results)
+    };
+    let v4 = by_version(IpVersion::V4);
+    let v6 = by_version(IpVersion::V6);
+
+    result!("");
+    result!("{:<16}{:>12}{:>12}{:>12}", "", "IPv4", "IPv6", "IPv6/IPv4");
+    let ratio = |v4_value: f64, v6_value: f64| -> String {
+        if v4_value > 0.0 { format!("{:.2}", v6_value / v4_value) } else { "-".to_string() }
+    };
+    let rows = vec![(format.heading("Download"), format.value(v4.rx_speed_mbps.mean), format.value(v6.rx_speed_mbps.mean),
+                     ratio(v4.rx_speed_mbps.mean, v6.rx_speed_mbps.mean)),
+                    (format.heading("Upload"), format.value(v4.tx_speed_mbps.mean), format.value(v6.tx_speed_mbps.mean),
+                     ratio(v4.tx_speed_mbps.mean, v6.tx_speed_mbps.mean)),
+                    ("Latency (ms)".to_string(), format!("{:.2}", v4.latency_millis.mean), format!("{:.2}", v6.latency_millis.mean),
+                     ratio(v4.latency_millis.mean, v6.latency_millis.mean))];
+    for (name, v4_value, v6_value, ratio) in rows {
+        result!("{:<16}{:>12}{:>12}{:>12}", name, v4_value, v6_value, ratio);
+    }
+}
+
+
+fn print_ranked_servers(ranked: &Vec<(&TestServerConfig, Option<u64>)>, options: &ClientOptions) {
+    result!("");
+    result!("{:>8}  {:<30}{:<20}{:>14}", "id", "name", "country",
+            format!("{} ping (ms)", options.ping_method.label().to_uppercase()));
+    for &(server, latency) in ranked {
+        let latency = latency.map(|l| l.to_string()).unwrap_or("failed".to_string());
+        result!("{:>8}  {:<30}{:<20}{:>14}", server.id, server.name, server.country, latency);
+    }
+}
+
+
+/// Mean of every server's tests next to each other, download also as share of the
+/// fastest server so a single slow peer stands out.
+fn print_server_comparison(results: &Vec<TestResult>, format: &SpeedFormat) {
+    let mut server_ids: Vec<u64> = Vec::new();
+    for r in results {
+        if !server_ids.contains(&r.server_id) {
+            server_ids.push(r.server_id);
+        }
+    }
+    let summaries: Vec<(&TestResult, RunSummary)> = server_ids.iter().filter_map(|id| {
+        let server_results: Vec<TestResult> = results.iter().filter(|r| r.server_id == *id).cloned().collect();
+        results.iter().find(|r| r.server_id == *id).map(|r| (r, RunSummary::from_results(&server_results)))
+    }).collect();
+    let best_download = summaries.iter().map(|&(_, ref s)| s.rx_speed_mbps.mean).fold(0.0, f64::max);
+
+    result!("");
+    result!("{:>8}  {:<30}{:>14}{:>17}{:>15}{:>11}", "id", "name", "latency (ms)", format.heading("download"),
+            format.heading("upload"), "% of best");
+    for (first, summary) in summaries {
+        let share = if best_download > 0.0 {
+            format!("{:.0}%", summary.rx_speed_mbps.mean * 100.0 / best_download)
+        } else {
+            "-".to_string()
+        };
+        result!("{:>8}  {:<30}{:>14.2}{:>17}{:>15}{:>11}", first.server_id, first.server_name,
+                summary.latency_millis.mean, format.value(summary.rx_speed_mbps.mean),
+                format.value(summary.tx_speed_mbps.mean), share);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ServerPicker, ServerStrategy, RunOptions, servers_by_id, capped_by_budget, budget_used_up};
+    use budget::DataBudget;
+    use client::ClientOptions;
+    use config::TestServerConfig;
+    use std::env;
+    use std::fs;
+
+    fn ranked_servers() -> Vec<TestServerConfig> {
+        (1..4).map(|i| TestServerConfig { id: i, ..TestServerConfig::default() }).collect()
+    }
+
+    #[test]
+    fn parse_server_strategy_test() {
+        assert_eq!(Ok(ServerStrategy::RoundRobin), ServerStrategy::parse("round-robin"));
+        assert_eq!(Ok(ServerStrategy::RePingEach), ServerStrategy::parse("Re-Ping-Each"));
+        assert!(ServerStrategy::parse("closest").is_err());
+    }
+
+    #[test]
+    fn round_robin_picker_test() {
+        let servers = ranked_servers();
+        let options = ClientOptions::default();
+        let ranked = servers.iter().map(|s| (s, Some(s.id * 10))).collect();
+        let mut picker = ServerPicker::new(ServerStrategy::RoundRobin, ranked, &options);
+
+        let picks: Vec<(u64, Option<u64>, bool)> = (0..4).map(|i| {
+            let (server, latency, fresh) = picker.pick(i);
+            (server.id, latency, fresh)
+        }).collect();
+        assert_eq!(vec![(1, Some(10), true), (2, Some(20), true), (3, Some(30), true), (1, Some(10), false)], picks);
+    }
+
+    #[test]
+    fn fixed_and_random_picker_test() {
+        let servers = ranked_servers();
+        let options = ClientOptions::default();
+        let ranked: Vec<(&TestServerConfig, Option<u64>)> = servers.iter().map(|s| (s, Some(s.id))).collect();
+
+        let mut fixed = ServerPicker::new(ServerStrategy::Fixed, ranked.clone(), &options);
+        assert_eq!((1, true), { let (s, _, f) = fixed.pick(0); (s.id, f) });
+        assert_eq!((1, false), { let (s, _, f) = fixed.pick(1); (s.id, f) });
+
+        let mut random = ServerPicker::new(ServerStrategy::Random, ranked, &options);
+        for i in 0..10 {
+            assert!(random.pick(i).0.id <= 3);
+        }
+    }
+
+    #[test]
+    fn servers_by_id_test() {
+        let servers: Vec<TestServerConfig> = (1..5).map(|i| TestServerConfig { id: i, ..TestServerConfig::default() }).collect();
+        let picked = servers_by_id(&servers, &[3, 9, 1]);
+        assert_eq!(vec![3, 1], picked.iter().map(|s| s.id).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn budget_caps_test_bytes_test() {
+        let mut path = env::temp_dir();
+        path.push(format!("stest-usage-{}", ::rand::random::<u32>()));
+        let budget = DataBudget::new(&path.to_string_lossy(), 1000);
+        budget.record(400).unwrap();
+
+        let mut options = ClientOptions::default();
+        assert_eq!(Some(600), capped_by_budget(&options, Some(&budget)).unwrap().network.max_bytes);
+        options.network.max_bytes = Some(100);
+        assert_eq!(Some(100), capped_by_budget(&options, Some(&budget)).unwrap().network.max_bytes);
+        assert_eq!(Some(100), capped_by_budget(&options, None).unwrap().network.max_bytes);
+
+        budget.record(600).unwrap();
+        let run_options = RunOptions { budget: Some(budget), ..RunOptions::default() };
+        assert!(budget_used_up(&run_options).unwrap());
+        let ping_only = RunOptions { download: false, upload: false, ..run_options };
+        assert!(!budget_used_up(&ping_only).unwrap());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use clap::{App, AppSettings, ArgMatches};
+use toml;
+use toml::Value;
+
+const CONFIG_DIR_NAME: &'static str = ".config/stest";
+const CONFIG_FILE_NAME: &'static str = "config.toml";
+const PROFILES_KEY: &'static str = "profiles";
+
+/// Options that conflict with each other (clap groups and `conflicts_with`), giving one of
+/// them on the command line drops file values of the others
+const EXCLUSIVE_OPTIONS: &'static [&'static [&'static str]] = &[
+    &["server-country", "server-country-code"],
+    &["ipv4", "ipv6", "dual_stack"],
+    &["compare", "compare_ids", "server_strategy"],
+    &["ping_only", "no_download"],
+    &["ping_only", "no_upload"],
+    &["simple", "tui"],
+    &["verbose", "quiet"]
+];
+
+
+/// Parsed config file. Keys are long option names (e.g. `server-country-code`), values
+/// at the top level apply to every run and tables under `[profiles.<name>]` override
+/// them when that profile is selected.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    table: toml::Table
+}
+
+
+impl ConfigFile {
+    pub fn parse(content: &str) -> Result<ConfigFile, String> {
+        let mut parser = toml::Parser::new(content);
+        match parser.parse() {
+            Some(table) => Ok(ConfigFile { table: table }),
+            None        => {
+                let errors: Vec<String> = parser.errors.iter().map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("line {}, column {}: {}", line + 1, col + 1, e.desc)
+                }).collect();
+                Err(format!("Invalid config file - {}", errors.join("; ")))
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<ConfigFile, String> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|e| format!("Cannot read config file {} - {}", path.display(), e))?;
+        ConfigFile::parse(&content)
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        match self.table.get(PROFILES_KEY) {
+            Some(&Value::Table(ref profiles))   => profiles.keys().cloned().collect(),
+            _                                   => Vec::new()
+        }
+    }
+
+    /// Top level values merged with values of given profile
+    pub fn values(&self, profile: Option<&str>) -> Result<BTreeMap<String, Value>, String> {
+        let mut values: BTreeMap<String, Value> = self.table.iter()
+            .filter(|&(k, _)| k != PROFILES_KEY)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        if let Some(name) = profile {
+            let profile_values = self.table.get(PROFILES_KEY)
+                .and_then(|p| p.as_table())
+                .and_then(|p| p.get(name))
+                .and_then(|p| p.as_table());
+            match profile_values {
+                Some(p) => values.extend(p.iter().map(|(k, v)| (k.clone(), v.clone()))),
+                None    => return Err(format!("Unknown profile '{}', available profiles: {}",
+                                              name, self.profile_names().join(", ")))
+            }
+        }
+        Ok(values)
+    }
+}
+
+
+/// ~/.config/stest/config.toml, None when home directory is not known
+pub fn default_config_path() -> Option<PathBuf> {
+    env::var("HOME").or(env::var("USERPROFILE")).ok().map(|h| {
+        let mut path = PathBuf::from(h);
+        path.push(CONFIG_DIR_NAME);
+        path.push(CONFIG_FILE_NAME);
+        path
+    })
+}
+
+
+/// Option values from command line falling back to the config file, so flags always
+/// override file values. Names are the clap argument names.
+pub struct Settings<'a> {
+    matches: &'a ArgMatches<'a>,
+    file_values: BTreeMap<String, Value>
+}
+
+
+impl<'a> Settings<'a> {
+    /// Settings of `--config` file (or the default one when it exists) and `--profile`
+    pub fn load(matches: &'a ArgMatches<'a>) -> Result<Settings<'a>, String> {
+        let config_file = match matches.value_of("config") {
+            Some(path)  => ConfigFile::load(Path::new(path))?,
+            None        => match default_config_path() {
+                Some(ref path) if path.exists() => ConfigFile::load(path)?,
+                _                               => ConfigFile::default()
+            }
+        };
+        let file_values = config_file.values(matches.value_of("profile"))?;
+        Ok(Settings::new(matches, file_values))
+    }
+
+    pub fn new(matches: &'a ArgMatches<'a>, file_values: BTreeMap<String, Value>) -> Settings<'a> {
+        Settings { matches: matches, file_values: file_values }
+    }
+
+    /// Checks file values left after the command line like clap checks the command line,
+    /// so misspelled options, invalid values and conflicting options are reported
+    pub fn validate(&self, app: App) -> Result<(), String> {
+        let mut args = vec!["stest".to_string()];
+        for (key, value) in &self.file_values {
+            let names = [key.replace("-", "_"), key.replace("_", "-")];
+            if names.iter().any(|n| self.given(n).is_some() || self.file_value(n).is_none()) {
+                continue;
+            }
+            args.extend(to_args(&names[1], value)?);
+        }
+        app.setting(AppSettings::ColorNever)
+            .get_matches_from_safe(args)
+            .map(|_| ())
+            .map_err(|e| {
+                let message = e.message.lines().next().unwrap_or("").trim_start_matches("error: ").to_string();
+                format!("Invalid config file - {}", message)
+            })
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<String> {
+        match self.given(name).and_then(|m| m.value_of(name)) {
+            Some(v) => Some(v.to_string()),
+            None    => self.file_value(name).and_then(to_string)
+        }
+    }
+
+    pub fn values_of(&self, name: &str) -> Vec<String> {
+        match self.given(name).and_then(|m| m.values_of(name)) {
+            Some(values)    => values.map(|v| v.to_string()).collect(),
+            None            => match self.file_value(name) {
+                Some(&Value::Array(ref values)) => values.iter().filter_map(to_string).collect(),
+                Some(v)                         => to_string(v).into_iter().collect(),
+                None                            => Vec::new()
+            }
+        }
+    }
+
+    pub fn is_present(&self, name: &str) -> bool {
+        self.given(name).is_some() ||
+            self.file_value(name).map(|v| v.as_bool().unwrap_or(true)).unwrap_or(false)
+    }
+
+    /// Times a flag was given on the command line, e.g. 2 for -vv
+    pub fn occurrences_of(&self, name: &str) -> u64 {
+        self.given(name).map(|m| m.occurrences_of(name)).unwrap_or(0)
+    }
+
+    /// Matches the argument was given in on the command line. Global arguments given after
+    /// a subcommand (`stest history --history-db x`) are only in the subcommand's matches,
+    /// clap does not pass them back up.
+    fn given(&self, name: &str) -> Option<&'a ArgMatches<'a>> {
+        let subcommand = self.matches.subcommand().1;
+        subcommand.into_iter().chain(Some(self.matches)).find(|m| m.is_present(name))
+    }
+
+    /// Keys in the file use the long option name, underscores are accepted as well. Values
+    /// of options excluded by another one given on the command line are left out.
+    fn file_value(&self, name: &str) -> Option<&Value> {
+        let excluded = EXCLUSIVE_OPTIONS.iter()
+            .filter(|set| set.contains(&name))
+            .any(|set| set.iter().any(|&other| other != name && self.given(other).is_some()));
+        if excluded {
+            return None;
+        }
+        self.file_values.get(&name.replace("_", "-"))
+            .or(self.file_values.get(&name.replace("-", "_")))
+    }
+}
+
+
+/// Command line arguments giving the option `--name` the file value, `verbose = 2` is
+/// the same as -vv
+fn to_args(name: &str, value: &Value) -> Result<Vec<String>, String> {
+    let flag = format!("--{}", name);
+    match *value {
+        Value::Boolean(true)                        => Ok(vec![flag]),
+        Value::Boolean(false)                       => Ok(Vec::new()),
+        Value::Integer(n) if name == "verbose"      => Ok((0..n).map(|_| flag.clone()).collect()),
+        Value::Array(ref values)                    => values.iter()
+            .map(|v| to_string(v).map(|v| format!("{}={}", flag, v)))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| format!("Invalid config file - invalid value of {}", name)),
+        ref v                                       => to_string(v)
+            .map(|v| vec![format!("{}={}", flag, v)])
+            .ok_or_else(|| format!("Invalid config file - invalid value of {}", name))
+    }
+}
+
+
+fn to_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s)    => Some(s.clone()),
+        Value::Integer(i)       => Some(i.to_string()),
+        Value::Float(f)         => Some(f.to_string()),
+        Value::Boolean(b)       => Some(b.to_string()),
+        Value::Datetime(ref d)  => Some(d.clone()),
+        _                       => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigFile, Settings};
+    use clap::{App, Arg, SubCommand};
+
+    const CONFIG: &'static str = r#"
+        connections = 4
+        https = true
+
+        [profiles.office]
+        server-country-code = "GB"
+        min-download = "50,20"
+        header = ["X-Probe: office", "X-Site: london"]
+
+        [profiles.lte-backup]
+        interface = "wwan0"
+        connections = 2
+    "#;
+
+    fn app<'a, 'b>() -> App<'a, 'b> {
+        App::new("stest")
+            .arg(Arg::with_name("connections").long("connections").takes_value(true))
+            .arg(Arg::with_name("interface").long("interface").takes_value(true))
+            .arg(Arg::with_name("https").long("https"))
+            .arg(Arg::with_name("server-country-code").long("server-country-code").takes_value(true))
+            .arg(Arg::with_name("min_download").long("min-download").takes_value(true))
+            .arg(Arg::with_name("header").long("header").takes_value(true).multiple(true).number_of_values(1))
+    }
+
+    #[test]
+    fn profile_overrides_top_level_test() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let values = file.values(Some("lte-backup")).unwrap();
+        let matches = app().get_matches_from(vec!["stest"]);
+        let settings = Settings::new(&matches, values);
+        assert_eq!(Some("2".to_string()), settings.value_of("connections"));
+        assert_eq!(Some("wwan0".to_string()), settings.value_of("interface"));
+        assert!(settings.is_present("https"));
+        assert_eq!(None, settings.value_of("server-country-code"));
+    }
+
+    #[test]
+    fn command_line_overrides_file_test() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let values = file.values(Some("office")).unwrap();
+        let matches = app().get_matches_from(vec!["stest", "--connections", "8"]);
+        let settings = Settings::new(&matches, values);
+        assert_eq!(Some("8".to_string()), settings.value_of("connections"));
+        assert_eq!(Some("GB".to_string()), settings.value_of("server-country-code"));
+        assert_eq!(Some("50,20".to_string()), settings.value_of("min_download"));
+        assert_eq!(vec!["X-Probe: office".to_string(), "X-Site: london".to_string()],
+                   settings.values_of("header"));
+    }
+
+    #[test]
+    fn global_after_subcommand_test() {
+        let app = App::new("stest")
+            .arg(Arg::with_name("history_db").long("history-db").takes_value(true).global(true))
+            .subcommand(SubCommand::with_name("history"));
+        let matches = app.get_matches_from(vec!["stest", "history", "--history-db", "/tmp/h.db"]);
+        let settings = Settings::new(&matches, ConfigFile::default().values(None).unwrap());
+        assert_eq!(Some("/tmp/h.db".to_string()), settings.value_of("history_db"));
+        assert!(settings.is_present("history_db"));
+    }
+
+    #[test]
+    fn speed_format_after_subcommand_test() {
+        let app = App::new("stest")
+            .arg(Arg::with_name("units").long("units").takes_value(true).global(true))
+            .arg(Arg::with_name("precision").long("precision").takes_value(true).global(true))
+            .subcommand(SubCommand::with_name("history"));
+        let file = ConfigFile::parse("units = \"MB/s\"\nprecision = 3").unwrap();
+        let matches = app.get_matches_from(vec!["stest", "history", "--units", "kbps", "--precision", "1"]);
+        let settings = Settings::new(&matches, file.values(None).unwrap());
+        assert_eq!(Some("kbps".to_string()), settings.value_of("units"));
+        assert_eq!(Some("1".to_string()), settings.value_of("precision"));
+    }
+
+    #[test]
+    fn flags_after_subcommand_test() {
+        let app = App::new("stest")
+            .arg(Arg::with_name("verbose").short("v").multiple(true).global(true))
+            .arg(Arg::with_name("quiet").short("q").global(true))
+            .subcommand(SubCommand::with_name("daemon"));
+        let matches = app.get_matches_from(vec!["stest", "daemon", "-vv"]);
+        let settings = Settings::new(&matches, ConfigFile::default().values(None).unwrap());
+        assert_eq!(2, settings.occurrences_of("verbose"));
+        assert!(!settings.is_present("quiet"));
+
+        let matches = App::new("stest").arg(Arg::with_name("verbose").short("v").multiple(true))
+            .get_matches_from(vec!["stest"]);
+        let settings = Settings::new(&matches, ConfigFile::default().values(None).unwrap());
+        assert_eq!(0, settings.occurrences_of("verbose"));
+    }
+
+    #[test]
+    fn command_line_excludes_conflicting_file_values_test() {
+        let file = ConfigFile::parse(r#"
+            ipv4 = true
+            compare = 3
+            https = true
+
+            [profiles.office]
+            server-country = "United Kingdom"
+        "#).unwrap();
+        let app = app()
+            .arg(Arg::with_name("server-country").long("server-country").takes_value(true))
+            .arg(Arg::with_name("ipv4").long("ipv4"))
+            .arg(Arg::with_name("ipv6").long("ipv6"))
+            .arg(Arg::with_name("compare").long("compare").takes_value(true))
+            .arg(Arg::with_name("server_strategy").long("server-strategy").takes_value(true));
+        let matches = app.get_matches_from(vec!["stest", "--server-country-code", "GB", "--ipv6",
+                                                "--server-strategy", "random"]);
+        let settings = Settings::new(&matches, file.values(Some("office")).unwrap());
+        assert_eq!(None, settings.value_of("server-country"));
+        assert!(!settings.is_present("ipv4"));
+        assert!(settings.is_present("ipv6"));
+        assert_eq!(None, settings.value_of("compare"));
+        assert!(settings.is_present("https"));
+    }
+
+    #[test]
+    fn validate_test() {
+        let app = || app()
+            .arg(Arg::with_name("simple").long("simple").conflicts_with("tui"))
+            .arg(Arg::with_name("tui").long("tui"))
+            .arg(Arg::with_name("units").long("units").takes_value(true).possible_values(&["Mbps", "MB/s"]));
+        let matches = app().get_matches_from(vec!["stest"]);
+        let validate = |content: &str| {
+            let values = ConfigFile::parse(content).unwrap().values(None).unwrap();
+            Settings::new(&matches, values).validate(app())
+        };
+
+        assert_eq!(Ok(()), validate(CONFIG));
+        assert_eq!(Ok(()), validate("units = \"MB/s\"\nheader = [\"X-Probe: office\"]\nhttps = false"));
+        assert!(validate("conections = 4").unwrap_err().contains("--conections"));
+        assert!(validate("units = \"furlongs\"").unwrap_err().contains("furlongs"));
+        assert!(validate("simple = true\ntui = true").unwrap_err().contains("cannot be used with"));
+
+        // Conflicting file value is dropped by the command line one
+        let matches = app().get_matches_from(vec!["stest", "--tui"]);
+        let values = ConfigFile::parse("simple = true").unwrap().values(None).unwrap();
+        assert_eq!(Ok(()), Settings::new(&matches, values).validate(app()));
+    }
+
+    #[test]
+    fn unknown_profile_test() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let error = file.values(Some("home")).unwrap_err();
+        assert!(error.contains("lte-backup, office"));
+    }
+
+    #[test]
+    fn invalid_file_test() {
+        assert!(ConfigFile::parse("connections = ").is_err());
+    }
+}
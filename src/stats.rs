@@ -0,0 +1,136 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+
+/// Summary of a set of measurements taken over a run, e.g. download speed of each test.
+#[derive(Debug, Default, Clone)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub p90: f64,
+    pub stddev: f64
+}
+
+
+impl Summary {
+    pub fn from_values(values: &[f64]) -> Summary {
+        if values.is_empty() {
+            return Summary::default();
+        }
+
+        Summary {
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            mean: mean(values),
+            median: percentile(values, 50.0),
+            p90: percentile(values, 90.0),
+            stddev: stddev(values)
+        }
+    }
+}
+
+
+impl ToJson for Summary {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("min".to_string(), self.min.to_json());
+        obj.insert("max".to_string(), self.max.to_json());
+        obj.insert("mean".to_string(), self.mean.to_json());
+        obj.insert("median".to_string(), self.median.to_json());
+        obj.insert("p90".to_string(), self.p90.to_json());
+        obj.insert("stddev".to_string(), self.stddev.to_json());
+        Json::Object(obj)
+    }
+}
+
+
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().fold(0.0, |acc, v| acc + v) / values.len() as f64
+}
+
+
+/// Population standard deviation
+pub fn stddev(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let avg = mean(values);
+    let variance = values.iter().fold(0.0, |acc, v| acc + (v - avg) * (v - avg)) / values.len() as f64;
+    variance.sqrt()
+}
+
+
+/// Percentile using linear interpolation between closest ranks, `pct` is 0-100.
+pub fn percentile(values: &[f64], pct: f64) -> f64 {
+    // NaN has no order, it is left out
+    let mut sorted: Vec<f64> = values.iter().cloned().filter(|v| !v.is_nan()).collect();
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    let rank = (pct / 100.0) * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let weight = rank - lower as f64;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * weight
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{mean, percentile, stddev, Summary};
+
+    #[test]
+    fn mean_test() {
+        assert_eq!(2.5, mean(&[1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(0.0, mean(&[]));
+    }
+
+    #[test]
+    fn stddev_test() {
+        assert_eq!(2.0, stddev(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]));
+        assert_eq!(0.0, stddev(&[3.0]));
+    }
+
+    #[test]
+    fn percentile_test() {
+        let values = vec![15.0, 20.0, 35.0, 40.0, 50.0];
+        assert_eq!(15.0, percentile(&values, 0.0));
+        assert_eq!(35.0, percentile(&values, 50.0));
+        assert_eq!(50.0, percentile(&values, 100.0));
+        assert_eq!(45.0, percentile(&values, 87.5));
+    }
+
+    #[test]
+    fn percentile_unsorted_input_test() {
+        let values = vec![50.0, 15.0, 40.0, 20.0, 35.0];
+        assert_eq!(35.0, percentile(&values, 50.0));
+    }
+
+    #[test]
+    fn percentile_with_nan_test() {
+        let values = vec![20.0, ::std::f64::NAN, 10.0, 30.0];
+        assert_eq!(20.0, percentile(&values, 50.0));
+        assert_eq!(30.0, percentile(&values, 100.0));
+        assert_eq!(0.0, percentile(&[::std::f64::NAN], 50.0));
+    }
+
+    #[test]
+    fn summary_test() {
+        let summary = Summary::from_values(&[4.0, 1.0, 3.0, 2.0, 5.0]);
+        assert_eq!(1.0, summary.min);
+        assert_eq!(5.0, summary.max);
+        assert_eq!(3.0, summary.mean);
+        assert_eq!(3.0, summary.median);
+        assert!((summary.p90 - 4.6).abs() < 1e-9);
+        assert_eq!(2.0_f64.sqrt(), summary.stddev);
+    }
+}
@@ -4,6 +4,11 @@ pub fn get_current_time_as_string() -> String {
     chrono::Local::now().to_string()
 }
 
+/// UTC timestamp that sorts lexically, used as the key in the history database
+pub fn get_current_utc_time_as_string() -> String {
+    chrono::UTC::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
 fn get_elapsed_in_millis() {
 
-}
\ No newline at end of file
+}
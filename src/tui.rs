@@ -0,0 +1,261 @@
+use std::io;
+use std::io::Write;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use config::TestServerConfig;
+use logging;
+use output;
+use progress::{Phase, Progress, ProgressEvent};
+use results::TestResult;
+use units::SpeedFormat;
+use describe_latency;
+
+const REFRESH_MILLIS: u64 = 200;
+const SPARKLINE_WIDTH: usize = 60;
+const SPARKLINE_TICKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+const ENTER_ALTERNATE_SCREEN: &'static str = "\x1b[?1049h\x1b[?25l";
+const LEAVE_ALTERNATE_SCREEN: &'static str = "\x1b[?25h\x1b[?1049l";
+const CLEAR_SCREEN: &'static str = "\x1b[H\x1b[2J";
+const BOLD: &'static str = "\x1b[1m";
+const RESET: &'static str = "\x1b[0m";
+
+
+/// Throughput samples of a download or upload phase
+#[derive(Debug, Clone, Default)]
+pub struct Gauge {
+    pub samples: Vec<f64>,
+    pub current_mbps: f64,
+    pub peak_mbps: f64
+}
+
+
+impl Gauge {
+    fn add(&mut self, speed_mbps: f64) {
+        self.samples.push(speed_mbps);
+        self.current_mbps = speed_mbps;
+        if speed_mbps > self.peak_mbps {
+            self.peak_mbps = speed_mbps;
+        }
+    }
+}
+
+
+/// What the terminal UI shows, built up from progress events
+#[derive(Debug, Clone, Default)]
+pub struct Dashboard {
+    pub server: Option<(TestServerConfig, Option<u64>)>,
+    pub test_number: u64,
+    pub phase: Option<Phase>,
+    pub latency_millis: Option<u64>,
+    pub loaded_latency_millis: Option<u64>,
+    pub download: Gauge,
+    pub upload: Gauge,
+    pub results: Vec<TestResult>,
+    pub speed_format: SpeedFormat
+}
+
+
+impl Dashboard {
+    pub fn apply(&mut self, event: &ProgressEvent) {
+        match *event {
+            ProgressEvent::Server { ref server, latency_millis }    => {
+                self.server = Some((server.clone(), latency_millis));
+            },
+            ProgressEvent::Phase { test_number, phase }             => {
+                if phase == Phase::Latency {
+                    // Gauges show the test in progress only
+                    self.download = Gauge::default();
+                    self.upload = Gauge::default();
+                    self.loaded_latency_millis = None;
+                }
+                self.test_number = test_number;
+                self.phase = Some(phase);
+            },
+            ProgressEvent::Throughput { phase, speed_mbps, .. }     => match phase {
+                Phase::Download => self.download.add(speed_mbps),
+                Phase::Upload   => self.upload.add(speed_mbps),
+                Phase::Latency  => {}
+            },
+            ProgressEvent::Latency { phase, millis }                => {
+                if phase == Phase::Latency {
+                    self.latency_millis = Some(millis);
+                } else {
+                    self.loaded_latency_millis = Some(millis);
+                }
+            },
+            ProgressEvent::Result(ref result)                       => {
+                self.results.push(result.clone());
+                self.phase = None;
+            },
+            ProgressEvent::Summary(_)                               => {}
+        }
+    }
+
+    /// Screen content without cursor movement, lines end with \r\n
+    pub fn render(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        let status = match self.phase {
+            Some(p) => format!("test {} - {}", self.test_number, p.label()),
+            None    if self.results.is_empty()  => "looking for the best server".to_string(),
+            None    => format!("{} test(s) done", self.results.len())
+        };
+        lines.push(format!("{}stest{} {}", BOLD, RESET, status));
+        lines.push(String::new());
+
+        match self.server {
+            Some((ref server, latency)) => {
+                lines.push(format!("Server    {} ({}) id {}", server.name, server.country, server.id));
+                lines.push(format!("          {}, {} when picked", server.host, describe_latency(latency)));
+            },
+            None                        => lines.push("Server    -".to_string())
+        }
+        let describe = |millis: Option<u64>| millis.map(|m| format!("{}ms", m)).unwrap_or("-".to_string());
+        lines.push(format!("Latency   {}   loaded {}", describe(self.latency_millis), describe(self.loaded_latency_millis)));
+        lines.push(String::new());
+
+        for &(name, ref gauge) in &[("Download", &self.download), ("Upload", &self.upload)] {
+            lines.push(format!("{:<10}{:>13}   peak {:>13}", name, self.speed_format.format(gauge.current_mbps),
+                               self.speed_format.format(gauge.peak_mbps)));
+            lines.push(format!("          {}", sparkline(&gauge.samples, SPARKLINE_WIDTH)));
+        }
+        lines.push(String::new());
+
+        if !self.results.is_empty() {
+            lines.push(format!("{:>4}{:>8}{:>14}{:>17}{:>15}{:>8}", "test", "server", "latency (ms)",
+                               self.speed_format.heading("download"), self.speed_format.heading("upload"), "grade"));
+            for r in &self.results {
+                let latency = if r.ping_failed { "failed".to_string() } else { r.latency_millis.to_string() };
+                lines.push(format!("{:>4}{:>8}{:>14}{:>17}{:>15}{:>8}", r.test_number, r.server_id, latency,
+                                   self.speed_format.value(r.rx_speed_mbps), self.speed_format.value(r.tx_speed_mbps),
+                                   if r.bufferbloat_grade.is_empty() { "-" } else { r.bufferbloat_grade.as_str() }));
+            }
+        }
+        lines.join("\r\n")
+    }
+}
+
+
+/// Latest `width` values as block characters scaled to the highest of them
+pub fn sparkline(values: &[f64], width: usize) -> String {
+    let start = if values.len() > width { values.len() - width } else { 0 };
+    let shown = &values[start..];
+    let max = shown.iter().cloned().fold(0.0, f64::max);
+    shown.iter().map(|v| {
+        if max <= 0.0 {
+            SPARKLINE_TICKS[0]
+        } else {
+            let idx = ((v / max) * (SPARKLINE_TICKS.len() - 1) as f64).round() as usize;
+            SPARKLINE_TICKS[idx.min(SPARKLINE_TICKS.len() - 1)]
+        }
+    }).collect()
+}
+
+
+/// Full screen dashboard redrawn every REFRESH_MILLIS on the alternate screen, so
+/// anything else printed meanwhile is painted over and the normal screen is left as it
+/// was.
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+    was_quiet: bool
+}
+
+
+impl Tui {
+    /// Chatter is dropped and log lines are held back while the dashboard is shown
+    pub fn start(progress: &Progress, speed_format: SpeedFormat) -> Tui {
+        let was_quiet = output::is_quiet();
+        output::set_quiet(true);
+        logging::hold();
+        let events = progress.subscribe();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = stop.clone();
+
+        let handle = thread::spawn(move || {
+            let refresh = Duration::from_millis(REFRESH_MILLIS);
+            let mut dashboard = Dashboard { speed_format: speed_format, ..Dashboard::default() };
+            let mut last_draw = Instant::now();
+            print!("{}", ENTER_ALTERNATE_SCREEN);
+
+            while !stop_flag.load(Ordering::SeqCst) {
+                match events.recv_timeout(refresh) {
+                    Ok(event)                           => dashboard.apply(&event),
+                    Err(RecvTimeoutError::Timeout)      => {},
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+                if last_draw.elapsed() >= refresh {
+                    print!("{}{}", CLEAR_SCREEN, dashboard.render());
+                    io::stdout().flush().ok();
+                    last_draw = Instant::now();
+                }
+            }
+            // Events emitted just before stopping, e.g. the last result
+            for event in events.try_iter() {
+                dashboard.apply(&event);
+            }
+            // Final state stays on the normal screen
+            print!("{}{}\r\n", LEAVE_ALTERNATE_SCREEN, dashboard.render());
+            io::stdout().flush().ok();
+        });
+
+        Tui { stop: stop, handle: handle, was_quiet: was_quiet }
+    }
+
+    /// Back to the normal screen, where the dashboard is printed one last time followed
+    /// by the log lines held back meanwhile
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.handle.join().ok();
+        output::set_quiet(self.was_quiet);
+        logging::release();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Dashboard, sparkline};
+    use config::TestServerConfig;
+    use progress::{Phase, ProgressEvent};
+    use results::TestResult;
+
+    #[test]
+    fn sparkline_test() {
+        assert_eq!("▁▄█", sparkline(&[0.0, 3.0, 7.0], 10));
+        assert_eq!("▄█", sparkline(&[7.0, 3.0, 7.0], 2));
+        assert_eq!("▁▁", sparkline(&[0.0, 0.0], 10));
+        assert_eq!("", sparkline(&[], 10));
+    }
+
+    #[test]
+    fn dashboard_follows_events_test() {
+        let mut dashboard = Dashboard::default();
+        let server = TestServerConfig { name: "Vodafone UK".to_string(), id: 1234, ..TestServerConfig::default() };
+        dashboard.apply(&ProgressEvent::Server { server: server, latency_millis: Some(21) });
+        dashboard.apply(&ProgressEvent::Phase { test_number: 1, phase: Phase::Download });
+        for speed in vec![40.0, 90.0, 80.0] {
+            dashboard.apply(&ProgressEvent::Throughput { phase: Phase::Download, total_bytes: 0,
+                                                         elapsed_millis: 0, speed_mbps: speed });
+        }
+        dashboard.apply(&ProgressEvent::Latency { phase: Phase::Download, millis: 48 });
+
+        assert_eq!(80.0, dashboard.download.current_mbps);
+        assert_eq!(90.0, dashboard.download.peak_mbps);
+        assert_eq!(Some(48), dashboard.loaded_latency_millis);
+        let screen = dashboard.render();
+        assert!(screen.contains("test 1 - download"));
+        assert!(screen.contains("Vodafone UK"));
+
+        let result = TestResult { test_number: 1, rx_speed_mbps: 85.0, ..TestResult::default() };
+        dashboard.apply(&ProgressEvent::Result(result));
+        dashboard.apply(&ProgressEvent::Phase { test_number: 2, phase: Phase::Latency });
+        assert!(dashboard.download.samples.is_empty());
+        assert!(dashboard.render().contains("85.00"));
+    }
+}
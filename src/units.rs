@@ -0,0 +1,190 @@
+use stats::Summary;
+
+const DEFAULT_PRECISION: usize = 2;
+
+
+/// Unit speeds are reported in, tests measure decimal megabits per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedUnit {
+    Kbps,
+    Mbps,
+    Gbps,
+    KBps,
+    MBps,
+    GBps,
+    KiBps,
+    MiBps,
+    GiBps
+}
+
+
+impl SpeedUnit {
+    pub fn parse(unit: &str) -> Result<SpeedUnit, String> {
+        match unit.to_lowercase().as_str() {
+            "kbps"  => Ok(SpeedUnit::Kbps),
+            "mbps"  => Ok(SpeedUnit::Mbps),
+            "gbps"  => Ok(SpeedUnit::Gbps),
+            "kb/s"  => Ok(SpeedUnit::KBps),
+            "mb/s"  => Ok(SpeedUnit::MBps),
+            "gb/s"  => Ok(SpeedUnit::GBps),
+            "kib/s" => Ok(SpeedUnit::KiBps),
+            "mib/s" => Ok(SpeedUnit::MiBps),
+            "gib/s" => Ok(SpeedUnit::GiBps),
+            _       => Err(format!("Invalid unit '{}', it should be one of kbps, Mbps, Gbps, kB/s, MB/s, GB/s, KiB/s, MiB/s or GiB/s", unit))
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match *self {
+            SpeedUnit::Kbps     => "kbps",
+            SpeedUnit::Mbps     => "Mbps",
+            SpeedUnit::Gbps     => "Gbps",
+            SpeedUnit::KBps     => "kB/s",
+            SpeedUnit::MBps     => "MB/s",
+            SpeedUnit::GBps     => "GB/s",
+            SpeedUnit::KiBps    => "KiB/s",
+            SpeedUnit::MiBps    => "MiB/s",
+            SpeedUnit::GiBps    => "GiB/s"
+        }
+    }
+
+    /// Suffix of csv column and json key names, e.g. rx_speed_mib_s
+    pub fn key(&self) -> &'static str {
+        match *self {
+            SpeedUnit::Kbps     => "kbps",
+            SpeedUnit::Mbps     => "mbps",
+            SpeedUnit::Gbps     => "gbps",
+            SpeedUnit::KBps     => "kb_s",
+            SpeedUnit::MBps     => "mb_s",
+            SpeedUnit::GBps     => "gb_s",
+            SpeedUnit::KiBps    => "kib_s",
+            SpeedUnit::MiBps    => "mib_s",
+            SpeedUnit::GiBps    => "gib_s"
+        }
+    }
+
+    /// Bits per second in one of this unit
+    fn bits_per_second(&self) -> f64 {
+        match *self {
+            SpeedUnit::Kbps     => 1e3,
+            SpeedUnit::Mbps     => 1e6,
+            SpeedUnit::Gbps     => 1e9,
+            SpeedUnit::KBps     => 8.0 * 1e3,
+            SpeedUnit::MBps     => 8.0 * 1e6,
+            SpeedUnit::GBps     => 8.0 * 1e9,
+            SpeedUnit::KiBps    => 8.0 * 1024.0,
+            SpeedUnit::MiBps    => 8.0 * 1024.0 * 1024.0,
+            SpeedUnit::GiBps    => 8.0 * 1024.0 * 1024.0 * 1024.0
+        }
+    }
+
+    pub fn from_mbps(&self, mbps: f64) -> f64 {
+        mbps * 1e6 / self.bits_per_second()
+    }
+}
+
+
+impl Default for SpeedUnit {
+    fn default() -> SpeedUnit {
+        SpeedUnit::Mbps
+    }
+}
+
+
+/// How speeds are shown on the console and written to csv/json files. Without a
+/// precision files get full precision and the console two decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SpeedFormat {
+    pub unit: SpeedUnit,
+    pub precision: Option<usize>
+}
+
+
+impl SpeedFormat {
+    /// Speed in this unit, rounded to precision when one is set
+    pub fn convert(&self, mbps: f64) -> f64 {
+        let value = self.unit.from_mbps(mbps);
+        match self.precision {
+            Some(p) => {
+                let factor = 10f64.powi(p as i32);
+                (value * factor).round() / factor
+            },
+            None    => value
+        }
+    }
+
+    /// Number only, for console tables
+    pub fn value(&self, mbps: f64) -> String {
+        format!("{:.*}", self.precision.unwrap_or(DEFAULT_PRECISION), self.unit.from_mbps(mbps))
+    }
+
+    /// Number and unit e.g. "93.42 Mbps"
+    pub fn format(&self, mbps: f64) -> String {
+        format!("{} {}", self.value(mbps), self.unit.label())
+    }
+
+    /// Value of a csv field
+    pub fn csv_value(&self, mbps: f64) -> String {
+        match self.precision {
+            Some(p) => format!("{:.*}", p, self.unit.from_mbps(mbps)),
+            None    => self.unit.from_mbps(mbps).to_string()
+        }
+    }
+
+    /// Column heading e.g. "download (Mbps)"
+    pub fn heading(&self, name: &str) -> String {
+        format!("{} ({})", name, self.unit.label())
+    }
+
+    /// Csv column and json key name e.g. rx_speed_mbps for prefix rx_speed
+    pub fn key(&self, prefix: &str) -> String {
+        format!("{}_{}", prefix, self.unit.key())
+    }
+
+    pub fn convert_summary(&self, summary: &Summary) -> Summary {
+        Summary {
+            min: self.convert(summary.min),
+            max: self.convert(summary.max),
+            mean: self.convert(summary.mean),
+            median: self.convert(summary.median),
+            p90: self.convert(summary.p90),
+            stddev: self.convert(summary.stddev)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{SpeedFormat, SpeedUnit};
+
+    #[test]
+    fn parse_unit_test() {
+        assert_eq!(Ok(SpeedUnit::Mbps), SpeedUnit::parse("Mbps"));
+        assert_eq!(Ok(SpeedUnit::MBps), SpeedUnit::parse("MB/s"));
+        assert_eq!(Ok(SpeedUnit::MiBps), SpeedUnit::parse("mib/s"));
+        assert!(SpeedUnit::parse("furlongs").is_err());
+    }
+
+    #[test]
+    fn convert_test() {
+        assert_eq!(100.0, SpeedUnit::Mbps.from_mbps(100.0));
+        assert_eq!(0.1, SpeedUnit::Gbps.from_mbps(100.0));
+        assert_eq!(12.5, SpeedUnit::MBps.from_mbps(100.0));
+        assert_eq!(1.0, SpeedUnit::MiBps.from_mbps(8.388608));
+
+        let format = SpeedFormat { unit: SpeedUnit::MiBps, precision: Some(1) };
+        assert_eq!(11.9, format.convert(100.0));
+        assert_eq!("11.9 MiB/s", format.format(100.0));
+        assert_eq!("rx_speed_mib_s", format.key("rx_speed"));
+    }
+
+    #[test]
+    fn default_format_test() {
+        let format = SpeedFormat::default();
+        assert_eq!(93.4567, format.convert(93.4567));
+        assert_eq!("93.46 Mbps", format.format(93.4567));
+        assert_eq!("93.4567", format.csv_value(93.4567));
+        assert_eq!("download (Mbps)", format.heading("download"));
+    }
+}
@@ -4,11 +4,15 @@ use std::io::Result;
 use std::io::{Error, ErrorKind};
 use std::cmp;
 
+use progress::ByteCounter;
+
 pub struct UploadData {
     pub total_data_size: u64,
     pub timeout_in_sec: u64,
     pub start_time: Instant,
-    pub current_size: u64
+    pub current_size: u64,
+    counter: Option<ByteCounter>,
+    max_bytes: Option<u64>
 }
 
 
@@ -18,9 +22,19 @@ impl UploadData {
             total_data_size: n,
             timeout_in_sec: timeout_secs,
             start_time: Instant::now(),
-            current_size: 0
+            current_size: 0,
+            counter: None,
+            max_bytes: None
         }
     }
+
+    /// Every chunk read is also added to given counter, body ends early once the
+    /// counter reaches max_bytes
+    pub fn with_counter(mut self, counter: ByteCounter, max_bytes: Option<u64>) -> UploadData {
+        self.counter = Some(counter);
+        self.max_bytes = max_bytes;
+        self
+    }
 }
 
 
@@ -44,6 +58,13 @@ impl Read for UploadData {
         let data_to_send = self.total_data_size - self.current_size;
 
         if data_to_send > 0 {
+            // Body ends once the counter reaches max bytes
+            if let Some(ref counter) = self.counter {
+                buf_size = counter.reserve(buf_size, self.max_bytes);
+                if buf_size == 0 {
+                    return Ok(0 as usize);
+                }
+            }
 //            let timer = Instant::now();
             for (idx, val) in chars.take(buf_size as usize).enumerate() {
                 buf[idx as usize] = val as u8;
@@ -64,6 +85,7 @@ impl Read for UploadData {
 mod test {
 
     use super::UploadData;
+    use progress::ByteCounter;
     use std::io::Read;
     use std::{time, thread};
     use std::time::Instant;
@@ -154,4 +176,17 @@ mod test {
         assert!(4 == num_cycles);
     }
 
+    #[test]
+    fn test_max_bytes_read_data() -> () {
+        let total_data = 8192 * 4; // 32KB
+        let counter = ByteCounter::default();
+        // Other uploads already sent 8K
+        counter.add(8192);
+        let mut buffered = UploadData::new(total_data, 1).with_counter(counter.clone(), Some(8192 * 3));
+        let mut data_read: Vec<u8> = vec![1; 8192];
+        while buffered.read(&mut data_read).unwrap() > 0 {}
+        assert!(buffered.current_size == 8192 * 2);
+        assert!(counter.total() == 8192 * 3);
+    }
+
 }
\ No newline at end of file
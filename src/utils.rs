@@ -1,5 +1,54 @@
-
+/// Speed is 0 when no time passed, e.g. all connections were refused straight away
 pub fn compute_speed_in_mbps(total_bytes: u64, total_time_in_millis: u64) -> f64 {
+    if total_time_in_millis == 0 {
+        return 0.0;
+    }
     let speed = (total_bytes as f64 * 8.0) / (total_time_in_millis as f64 / 1000.0);
     speed / (1000.0 * 1000.0)
-}
\ No newline at end of file
+}
+
+/// Parses a byte count like 500000, 500K, 250MB, 2G or 1GiB - K/M/G/T are powers of
+/// 1000, KiB/MiB/GiB/TiB powers of 1024.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid number of bytes '{}'", value);
+    let trimmed = value.trim();
+    let split_at = trimmed.find(|c: char| !(c.is_digit(10) || c == '.')).unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split_at);
+    let multiplier: u64 = match unit.trim().to_uppercase().as_str() {
+        "" | "B"            => 1,
+        "K" | "KB"          => 1_000,
+        "M" | "MB"          => 1_000_000,
+        "G" | "GB"          => 1_000_000_000,
+        "T" | "TB"          => 1_000_000_000_000,
+        "KIB"               => 1 << 10,
+        "MIB"               => 1 << 20,
+        "GIB"               => 1 << 30,
+        "TIB"               => 1 << 40,
+        _                   => return Err(invalid())
+    };
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_bytes, compute_speed_in_mbps};
+
+    #[test]
+    fn speed_without_time_test() {
+        assert_eq!(0.0, compute_speed_in_mbps(0, 0));
+        assert_eq!(8.0, compute_speed_in_mbps(1_000_000, 1_000));
+    }
+
+    #[test]
+    fn parse_bytes_test() {
+        assert_eq!(Ok(500000), parse_bytes("500000"));
+        assert_eq!(Ok(250_000_000), parse_bytes("250MB"));
+        assert_eq!(Ok(1_500_000_000), parse_bytes("1.5G"));
+        assert_eq!(Ok(2 * 1024 * 1024), parse_bytes("2 MiB"));
+        assert!(parse_bytes("lots").is_err());
+        assert!(parse_bytes("10 PB").is_err());
+        assert!(parse_bytes("").is_err());
+    }
+}
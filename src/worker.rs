@@ -0,0 +1,137 @@
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+
+/// Fixed number of worker threads taking jobs from a shared queue, so the number of
+/// threads does not grow with the number of requests. Workers that finish early pick
+/// up the remaining jobs of slower ones.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerPool {
+    workers: usize
+}
+
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> WorkerPool {
+        WorkerPool { workers: if workers == 0 { 1 } else { workers } }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Runs `job` for every item and returns the results in completion order. A job
+    /// that panics only loses its own result.
+    pub fn run<T, R, F>(&self, items: Vec<T>, job: F) -> Vec<R>
+        where T: Send + 'static,
+              R: Send + 'static,
+              F: Fn(T) -> R + Send + Sync + 'static {
+        let num_items = items.len();
+        let (item_tx, item_rx) = channel();
+        for item in items {
+            item_tx.send(item).ok();
+        }
+        drop(item_tx);
+
+        let queue = Arc::new(Mutex::new(item_rx));
+        let job = Arc::new(job);
+        let (result_tx, result_rx) = channel();
+
+        let mut thread_handles = vec![];
+        for n in 0..self.workers.min(num_items) {
+            let queue = queue.clone();
+            let job = job.clone();
+            let result_tx = result_tx.clone();
+            // Named so log lines show which worker they come from
+            let spawned = thread::Builder::new().name(format!("worker-{}", n + 1)).spawn(move || {
+                loop {
+                    // Lock is released before the job runs
+                    let item = match queue.lock() {
+                        Ok(q)   => q.recv(),
+                        Err(_)  => break
+                    };
+                    match item {
+                        Ok(i)   => run_job(&*job, i, &result_tx),
+                        Err(_)  => break
+                    }
+                }
+            });
+            match spawned {
+                Ok(handle)  => thread_handles.push(handle),
+                // Remaining workers take over its share of the queue
+                Err(e)      => warn!("Cannot start worker thread - {}", e)
+            }
+        }
+        drop(result_tx);
+
+        for h in thread_handles {
+            h.join().ok();
+        }
+        result_rx.iter().collect()
+    }
+}
+
+
+/// Catches a panicking job so it does not take the worker and its remaining jobs down
+fn run_job<T, R, F>(job: &F, item: T, results: &Sender<R>) where F: Fn(T) -> R {
+    let outcome = panic::catch_unwind(panic::AssertUnwindSafe(|| job(item)));
+    match outcome {
+        Ok(result)  => { results.send(result).ok(); },
+        Err(_)      => warn!("Job panicked, its result is lost")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerPool;
+    use std::cmp::max;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_every_item_test() {
+        let pool = WorkerPool::new(3);
+        let mut results = pool.run((0..20).collect(), |n: u64| n * 2);
+        results.sort();
+        assert_eq!((0..20).map(|n| n * 2).collect::<Vec<u64>>(), results);
+    }
+
+    #[test]
+    fn concurrency_is_bounded_test() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(Mutex::new(0));
+        let (r, m) = (running.clone(), max_running.clone());
+
+        let pool = WorkerPool::new(4);
+        let results = pool.run((0..32).collect(), move |n: u64| {
+            let now = r.fetch_add(1, Ordering::SeqCst) + 1;
+            {
+                let mut highest = m.lock().unwrap();
+                *highest = max(*highest, now);
+            }
+            thread::sleep(Duration::from_millis(5));
+            r.fetch_sub(1, Ordering::SeqCst);
+            n
+        });
+
+        assert_eq!(32, results.len());
+        assert!(*max_running.lock().unwrap() <= 4);
+    }
+
+    #[test]
+    fn panicking_job_loses_only_its_result_test() {
+        let pool = WorkerPool::new(2);
+        let results = pool.run((0..10).collect(), |n: u64| {
+            if n == 3 {
+                panic!("job failed");
+            }
+            n
+        });
+        assert_eq!(9, results.len());
+    }
+}